@@ -0,0 +1,486 @@
+//! Server-side copies of objects: with CopyObject, or in parts with UploadPartCopy for objects that
+//! are too large for CopyObject.
+use super::*;
+use crate::list_actions::copy_source;
+use aws_sdk_s3::operation::copy_object::builders::CopyObjectFluentBuilder;
+use aws_sdk_s3::types::{
+    CompletedMultipartUpload, CompletedPart, MetadataDirective, Object, TaggingDirective,
+};
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use std::time::Instant;
+
+/// The largest object that CopyObject can copy (5 GiB). Larger objects are copied in parts.
+const MAX_COPY_OBJECT_SIZE: i64 = 5 * 1024 * 1024 * 1024;
+/// The size of the parts of a multipart copy, unless the object needs larger parts.
+const COPY_PART_SIZE: i64 = 512 * 1024 * 1024;
+/// The maximum number of parts of a multipart upload.
+const MAX_PARTS: i64 = 10_000;
+/// Number of parts of one object that are copied at the same time.
+const PART_PARALLELIZATION: usize = 4;
+
+/// The byte ranges (first and last byte) of the parts of a multipart copy of `size` bytes, in
+/// parts of `part_size` bytes or larger if needed to stay within `MAX_PARTS`.
+fn part_ranges(size: i64, part_size: i64) -> Vec<(i64, i64)> {
+    let part_size = part_size.max((size + MAX_PARTS - 1) / MAX_PARTS);
+    (0..size)
+        .step_by(part_size as usize)
+        .map(|first| (first, (first + part_size).min(size) - 1))
+        .collect()
+}
+
+/// Tags (key-value pairs) in the URL query format of the `x-amz-tagging` header.
+pub(crate) fn tagging_header<'a, I>(tags: I) -> String
+where
+    I: IntoIterator<Item = (&'a str, &'a str)>,
+{
+    let encode = |s: &str| utf8_percent_encode(s, NON_ALPHANUMERIC).to_string();
+    tags.into_iter()
+        .map(|(key, value)| format!("{}={}", encode(key), encode(value)))
+        .collect::<Vec<_>>()
+        .join("&")
+}
+
+/// The object that a copy reads.
+#[derive(Clone, Debug)]
+pub(crate) struct CopySource {
+    pub bucket: String,
+    pub key: String,
+    /// The version to copy, or `None` for the current version.
+    pub version_id: Option<String>,
+    /// Size in bytes.
+    pub size: i64,
+}
+
+impl CopySource {
+    /// The value of the `x-amz-copy-source` header.
+    fn header(&self) -> String {
+        copy_source(&self.bucket, &self.key, self.version_id.as_deref())
+    }
+}
+
+/// Copy `source` with the destination and options of `request`, with the retries and timeouts of
+/// `s3_request`. `request` must have the destination bucket and key; the copy source and the
+/// `encryption` settings are set here.
+///
+/// Objects larger than CopyObject supports are copied in parts (see `copy_in_parts`).
+pub(crate) async fn copy_object(
+    s3: Client,
+    encryption: Encryption,
+    source: CopySource,
+    request: CopyObjectFluentBuilder,
+    n_retries: usize,
+    timeout: Arc<Mutex<TimeoutState>>,
+) -> Result<RequestReport, Error> {
+    if source.size > MAX_COPY_OBJECT_SIZE {
+        return copy_in_parts(
+            s3,
+            encryption,
+            source,
+            COPY_PART_SIZE,
+            request,
+            n_retries,
+            timeout,
+        )
+        .await;
+    }
+    let size = source.size as usize;
+    let request =
+        encryption.copy_object_source(encryption.copy_object(request.copy_source(source.header())));
+    let (report, _) = s3_request(
+        move || {
+            let request = request.clone();
+            async move { Ok((request.send().map_err(Error::from), size)) }
+        },
+        |_, size| size,
+        n_retries,
+        timeout.clone(),
+    )
+    .await?;
+    timeout.lock().await.update(&report);
+    Ok(report)
+}
+
+/// Send one of the requests of `copy_in_parts` other than the part copies, with the retries and
+/// timeouts of `s3_request`, as if it transferred `size` bytes. The timeout estimate is not
+/// updated, since these requests do not transfer object data.
+async fn send<F, G, R>(
+    request: F,
+    size: usize,
+    n_retries: usize,
+    timeout: Arc<Mutex<TimeoutState>>,
+) -> Result<R, Error>
+where
+    F: Fn() -> G + Unpin + Clone + Send + Sync + 'static,
+    G: Future<Output = Result<R, Error>> + Send,
+{
+    let (_, output) = s3_request(
+        move || {
+            let request = request.clone();
+            async move { Ok((request(), size)) }
+        },
+        |_, size| size,
+        n_retries,
+        timeout,
+    )
+    .await?;
+    Ok(output)
+}
+
+/// Copy an object in parts of `part_size` bytes with UploadPartCopy, with the options of `request`
+/// that CreateMultipartUpload supports: storage class, ACL, metadata, content headers and tags.
+///
+/// Unlike CopyObject, a multipart upload does not copy metadata and tags, so unless `request`
+/// replaces them (with `MetadataDirective::Replace` and `TaggingDirective::Replace`), they are
+/// read from the source first. Each part is retried on its own; if a part still fails, the upload
+/// is aborted. The other requests are retried like the parts.
+///
+/// The returned report covers the whole copy, with the most attempts of any part.
+async fn copy_in_parts(
+    s3: Client,
+    encryption: Encryption,
+    source: CopySource,
+    part_size: i64,
+    request: CopyObjectFluentBuilder,
+    n_retries: usize,
+    timeout: Arc<Mutex<TimeoutState>>,
+) -> Result<RequestReport, Error> {
+    let start = Instant::now();
+    let (bucket, key) = match (request.get_bucket(), request.get_key()) {
+        (Some(bucket), Some(key)) => (bucket.clone(), key.clone()),
+        _ => {
+            return Err(Error::MultipartCopy {
+                key: source.key,
+                reason: "no destination bucket or key".into(),
+            })
+        }
+    };
+    let create = s3
+        .create_multipart_upload()
+        .bucket(&bucket)
+        .key(&key)
+        .set_storage_class(request.get_storage_class().clone())
+        .set_acl(request.get_acl().clone());
+    let create = if request.get_metadata_directive() == &Some(MetadataDirective::Replace) {
+        create
+            .set_metadata(request.get_metadata().clone())
+            .set_content_type(request.get_content_type().clone())
+            .set_cache_control(request.get_cache_control().clone())
+            .set_content_disposition(request.get_content_disposition().clone())
+            .set_content_encoding(request.get_content_encoding().clone())
+            .set_content_language(request.get_content_language().clone())
+            .set_expires(*request.get_expires())
+            .set_website_redirect_location(request.get_website_redirect_location().clone())
+    } else {
+        let head = encryption.head_object(
+            s3.head_object()
+                .bucket(&source.bucket)
+                .key(&source.key)
+                .set_version_id(source.version_id.clone()),
+        );
+        let head = send(
+            move || head.clone().send().map_err(Error::from),
+            0,
+            n_retries,
+            timeout.clone(),
+        )
+        .await?;
+        create
+            .set_metadata(head.metadata)
+            .set_content_type(head.content_type)
+            .set_cache_control(head.cache_control)
+            .set_content_disposition(head.content_disposition)
+            .set_content_encoding(head.content_encoding)
+            .set_content_language(head.content_language)
+            .set_expires(head.expires)
+            .set_website_redirect_location(head.website_redirect_location)
+    };
+    let tagging = if request.get_tagging_directive() == &Some(TaggingDirective::Replace) {
+        request.get_tagging().clone()
+    } else {
+        let get_tagging = s3
+            .get_object_tagging()
+            .bucket(&source.bucket)
+            .key(&source.key)
+            .set_version_id(source.version_id.clone());
+        let tags = send(
+            move || get_tagging.clone().send().map_err(Error::from),
+            0,
+            n_retries,
+            timeout.clone(),
+        )
+        .await?
+        .tag_set
+        .unwrap_or_default();
+        let tags = tags.iter().map(|tag| {
+            (
+                tag.key.as_deref().unwrap_or_default(),
+                tag.value.as_deref().unwrap_or_default(),
+            )
+        });
+        Some(tagging_header(tags)).filter(|tagging| !tagging.is_empty())
+    };
+    let create = encryption.create_multipart_upload(create.set_tagging(tagging));
+    let upload_id = send(
+        move || create.clone().send().map_err(Error::from),
+        0,
+        n_retries,
+        timeout.clone(),
+    )
+    .await?
+    .upload_id
+    .ok_or_else(|| Error::MultipartCopy {
+        key: key.clone(),
+        reason: "no upload id".into(),
+    })?;
+
+    let copy_source = source.header();
+    let parts = stream::iter(part_ranges(source.size, part_size).into_iter().enumerate())
+        .map(|(i, (first, last))| {
+            let part_number = i as i32 + 1;
+            let request = encryption.upload_part_copy(
+                s3.upload_part_copy()
+                    .bucket(&bucket)
+                    .key(&key)
+                    .upload_id(&upload_id)
+                    .part_number(part_number)
+                    .copy_source(&copy_source)
+                    .copy_source_range(format!("bytes={}-{}", first, last)),
+            );
+            let (key, timeout) = (key.clone(), timeout.clone());
+            let size = (last - first + 1) as usize;
+            async move {
+                let (report, e_tag) = s3_request(
+                    move || {
+                        let request = request.clone();
+                        async move {
+                            Ok((
+                                request
+                                    .send()
+                                    .map_ok(|output| {
+                                        output.copy_part_result.and_then(|result| result.e_tag)
+                                    })
+                                    .map_err(Error::from),
+                                size,
+                            ))
+                        }
+                    },
+                    |_, size| size,
+                    n_retries,
+                    timeout.clone(),
+                )
+                .await?;
+                timeout.lock().await.update(&report);
+                let e_tag = e_tag.ok_or_else(|| Error::MultipartCopy {
+                    key,
+                    reason: format!("no ETag of part {}", part_number),
+                })?;
+                let part = CompletedPart::builder()
+                    .part_number(part_number)
+                    .e_tag(e_tag)
+                    .build();
+                Ok::<_, Error>((report.attempts, part))
+            }
+        })
+        .buffered(PART_PARALLELIZATION)
+        .try_collect::<Vec<_>>()
+        .await;
+    let parts = match parts {
+        Ok(parts) => parts,
+        Err(e) => {
+            // Don't leave the copied parts behind. The part error is the interesting one.
+            let abort = s3
+                .abort_multipart_upload()
+                .bucket(&bucket)
+                .key(&key)
+                .upload_id(&upload_id);
+            let _ = send(
+                move || abort.clone().send().map_err(Error::from),
+                0,
+                n_retries,
+                timeout.clone(),
+            )
+            .await;
+            return Err(e);
+        }
+    };
+    let attempts = parts
+        .iter()
+        .map(|(attempts, _)| *attempts)
+        .max()
+        .unwrap_or(1);
+    let parts = parts.into_iter().map(|(_, part)| part).collect();
+    let complete = s3
+        .complete_multipart_upload()
+        .bucket(&bucket)
+        .key(&key)
+        .upload_id(&upload_id)
+        .multipart_upload(
+            CompletedMultipartUpload::builder()
+                .set_parts(Some(parts))
+                .build(),
+        );
+    // S3 assembles the parts before it responds, which takes longer for larger objects
+    send(
+        move || complete.clone().send().map_err(Error::from),
+        source.size as usize,
+        n_retries,
+        timeout.clone(),
+    )
+    .await?;
+    let time = start.elapsed();
+    Ok(RequestReport {
+        seq: 0,
+        size: source.size as usize,
+        total_time: time,
+        success_time: time,
+        attempts,
+        est: timeout.lock().await.get_estimate(),
+    })
+}
+
+/// Copy objects of `bucket`, with up to `copy_parallelization` copies at the same time and the
+/// timeouts of `put_requests`. Each item of `requests` is a listed object and the request to copy
+/// it with (see `copy_object`).
+///
+/// Yields the source key and the `RequestReport` of each copied object, in order of completion.
+pub(crate) fn copy_objects<St>(
+    s3: Client,
+    config: &Config,
+    encryption: Encryption,
+    bucket: String,
+    requests: St,
+) -> impl Stream<Item = Result<(String, RequestReport), Error>> + Send
+where
+    St: Stream<Item = Result<(Object, CopyObjectFluentBuilder), Error>> + Send + 'static,
+{
+    let timeout = Arc::new(Mutex::new(TimeoutState::new(
+        config.algorithm.clone(),
+        config.put_requests.clone(),
+    )));
+    let n_retries = config.algorithm.n_retries;
+    requests
+        .map_ok(move |(object, request)| {
+            let (s3, encryption, bucket, timeout) = (
+                s3.clone(),
+                encryption.clone(),
+                bucket.clone(),
+                timeout.clone(),
+            );
+            async move {
+                let key = object.key.ok_or(Error::MissingKeyOrSize)?;
+                let source = CopySource {
+                    bucket,
+                    key: key.clone(),
+                    version_id: None,
+                    size: object.size,
+                };
+                let report =
+                    copy_object(s3, encryption, source, request, n_retries, timeout).await?;
+                Ok::<_, Error>((key, report))
+            }
+        })
+        .try_buffer_unordered(config.copy_parallelization)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::rand_string;
+    use aws_sdk_s3::types::StorageClass;
+
+    #[test]
+    fn split_into_parts() {
+        assert_eq!(part_ranges(10, 4), vec![(0, 3), (4, 7), (8, 9)]);
+        assert_eq!(part_ranges(8, 4), vec![(0, 3), (4, 7)]);
+        // At most 10 000 parts
+        assert_eq!(part_ranges(20_000, 1).len(), 10_000);
+    }
+
+    #[test]
+    fn encode_tagging() {
+        assert_eq!(
+            tagging_header(vec![("a", "1"), ("b c", "x&y=z")]),
+            "a=1&b%20c=x%26y%3Dz"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_s3_copy_in_parts() {
+        const MIB: usize = 1024 * 1024;
+        let s3 = testing_sdk_client().await;
+        let (src_key, dest_key) = (rand_string(14), rand_string(14));
+        let data = (0..6 * MIB).map(|i| i as u8).collect::<Vec<_>>();
+        s3.put_object()
+            .bucket("test-bucket")
+            .key(&src_key)
+            .body(data.clone().into())
+            .content_type("image/tiff")
+            .metadata("origin", "camera")
+            .tagging("project=x")
+            .send()
+            .await
+            .unwrap();
+
+        // Parts must be at least 5 MiB, except the last
+        let config = Config::default();
+        let timeout = Arc::new(Mutex::new(TimeoutState::new(
+            config.algorithm.clone(),
+            config.put_requests.clone(),
+        )));
+        let source = CopySource {
+            bucket: "test-bucket".into(),
+            key: src_key,
+            version_id: None,
+            size: data.len() as i64,
+        };
+        let report = copy_in_parts(
+            s3.clone(),
+            Encryption::None,
+            source,
+            5 * MIB as i64,
+            s3.copy_object()
+                .bucket("test-bucket")
+                .key(&dest_key)
+                .storage_class(StorageClass::ReducedRedundancy),
+            config.algorithm.n_retries,
+            timeout,
+        )
+        .await
+        .unwrap();
+        assert_eq!(report.size, data.len());
+
+        let head = s3
+            .head_object()
+            .bucket("test-bucket")
+            .key(&dest_key)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(head.content_type.as_deref(), Some("image/tiff"));
+        assert_eq!(head.metadata.unwrap()["origin"], "camera");
+        assert_eq!(head.storage_class, Some(StorageClass::ReducedRedundancy));
+        let tags = s3
+            .get_object_tagging()
+            .bucket("test-bucket")
+            .key(&dest_key)
+            .send()
+            .await
+            .unwrap()
+            .tag_set
+            .unwrap();
+        assert_eq!(tags[0].value.as_deref(), Some("x"));
+        let copied = s3
+            .get_object()
+            .bucket("test-bucket")
+            .key(&dest_key)
+            .send()
+            .await
+            .unwrap()
+            .body
+            .collect()
+            .await
+            .unwrap()
+            .into_bytes();
+        assert_eq!(copied.to_vec(), data);
+    }
+}
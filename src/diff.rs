@@ -0,0 +1,221 @@
+//! Comparison of two listings, by merge-joining them in key order.
+use super::*;
+use aws_sdk_s3::operation::list_objects_v2::ListObjectsV2Output;
+use aws_sdk_s3::types::Object;
+use futures::stream::BoxStream;
+use std::cmp::Ordering;
+
+/// A difference between two listings, found by `ListObjects::diff`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum DiffEntry {
+    /// The object is only in the left listing.
+    OnlyLeft(Object),
+    /// The object is only in the right listing.
+    OnlyRight(Object),
+    /// The object is in both listings, with different sizes.
+    SizeDiffers { left: Object, right: Object },
+    /// The object is in both listings with the same size, but with different ETags.
+    ETagDiffers { left: Object, right: Object },
+}
+
+type Mapping = Arc<dyn Fn(&str) -> String + Send + Sync>;
+
+/// One of the listings, with its next object and the key it is compared by.
+struct Side {
+    objects: BoxStream<'static, Result<Object, Error>>,
+    mapping: Option<Mapping>,
+    next: Option<(String, Object)>,
+}
+
+impl Side {
+    /// Fetch the next object, which must come after `previous`.
+    async fn fetch(&mut self, previous: Option<String>) -> Result<(), Error> {
+        let obj = match self.objects.try_next().await? {
+            Some(obj) => obj,
+            None => return Ok(()),
+        };
+        let key = obj.key.as_deref().unwrap_or_default();
+        let key = match &self.mapping {
+            Some(mapping) => mapping(key),
+            None => key.to_owned(),
+        };
+        if previous.is_some_and(|previous| previous >= key) {
+            return Err(Error::UnsortedListing { key });
+        }
+        self.next = Some((key, obj));
+        Ok(())
+    }
+
+    /// Take the next object, and fetch the one after it.
+    async fn pop(&mut self) -> Result<Object, Error> {
+        let (key, obj) = self.next.take().expect("no next object");
+        self.fetch(Some(key)).await?;
+        Ok(obj)
+    }
+}
+
+impl<S> ListObjects<S>
+where
+    S: Stream<Item = Result<ListObjectsV2Output, Error>> + Sized + Send + 'static,
+{
+    /// Compare this listing (left) with `other` (right), which may be of another prefix, bucket or
+    /// S3 endpoint, and yield the differences.
+    ///
+    /// `mapping` maps the keys of this listing to the keys they should have in `other`, for
+    /// example by substituting the prefix. Both listings are consumed once, in lexicographic key
+    /// order, like ListObjectsV2 lists them, so `mapping` must preserve that order. A listing that
+    /// is not in order (like `S3Algo::list_prefix_partitioned`) fails with
+    /// `Error::UnsortedListing`.
+    ///
+    /// ETags are only compared when the sizes are equal. Note that the ETag of an object uploaded
+    /// in multiple parts differs from that of the same data uploaded in one part.
+    pub fn diff<S2, F>(
+        self,
+        other: ListObjects<S2>,
+        mapping: F,
+    ) -> impl Stream<Item = Result<DiffEntry, Error>> + Send
+    where
+        S2: Stream<Item = Result<ListObjectsV2Output, Error>> + Sized + Send + 'static,
+        F: Fn(&str) -> String + Send + Sync + 'static,
+    {
+        let left = Side {
+            objects: self.flatten().boxed(),
+            mapping: Some(Arc::new(mapping)),
+            next: None,
+        };
+        let right = Side {
+            objects: other.flatten().boxed(),
+            mapping: None,
+            next: None,
+        };
+        stream::try_unfold(
+            (left, right, false),
+            |(mut left, mut right, started)| async move {
+                if !started {
+                    left.fetch(None).await?;
+                    right.fetch(None).await?;
+                }
+                loop {
+                    let order = match (&left.next, &right.next) {
+                        (None, None) => return Ok(None),
+                        (Some(_), None) => Ordering::Less,
+                        (None, Some(_)) => Ordering::Greater,
+                        (Some((left_key, _)), Some((right_key, _))) => left_key.cmp(right_key),
+                    };
+                    let entry = match order {
+                        Ordering::Less => DiffEntry::OnlyLeft(left.pop().await?),
+                        Ordering::Greater => DiffEntry::OnlyRight(right.pop().await?),
+                        Ordering::Equal => {
+                            let (left_obj, right_obj) = (left.pop().await?, right.pop().await?);
+                            if left_obj.size != right_obj.size {
+                                DiffEntry::SizeDiffers {
+                                    left: left_obj,
+                                    right: right_obj,
+                                }
+                            } else if left_obj.e_tag.is_some()
+                                && right_obj.e_tag.is_some()
+                                && left_obj.e_tag != right_obj.e_tag
+                            {
+                                DiffEntry::ETagDiffers {
+                                    left: left_obj,
+                                    right: right_obj,
+                                }
+                            } else {
+                                continue;
+                            }
+                        }
+                    };
+                    return Ok::<_, Error>(Some((entry, (left, right, true))));
+                }
+            },
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::FixedPages;
+
+    async fn listing(
+        prefix: &'static str,
+        pages: Vec<Vec<(&'static str, i64, &'static str)>>,
+    ) -> ListObjects<FixedPages> {
+        let pages = pages
+            .into_iter()
+            .map(|objects| {
+                objects
+                    .into_iter()
+                    .map(|(key, size, etag)| {
+                        Object::builder()
+                            .key(format!("{}{}", prefix, key))
+                            .size(size)
+                            .e_tag(etag)
+                            .build()
+                    })
+                    .collect()
+            })
+            .collect();
+        ListObjects::from_pages(prefix, pages).await
+    }
+
+    fn summary(entry: DiffEntry) -> (&'static str, String) {
+        let key = |obj: Object| obj.key.unwrap();
+        match entry {
+            DiffEntry::OnlyLeft(obj) => ("left", key(obj)),
+            DiffEntry::OnlyRight(obj) => ("right", key(obj)),
+            DiffEntry::SizeDiffers { right, .. } => ("size", key(right)),
+            DiffEntry::ETagDiffers { right, .. } => ("etag", key(right)),
+        }
+    }
+
+    #[tokio::test]
+    async fn diff_listings() {
+        let left = listing(
+            "a/",
+            vec![
+                vec![("1", 1, "x"), ("2", 1, "x"), ("3", 1, "x")],
+                vec![("5", 1, "x"), ("6", 1, "x"), ("8", 1, "x")],
+            ],
+        )
+        .await;
+        let right = listing(
+            "b/",
+            vec![
+                vec![("0", 1, "x"), ("2", 2, "x")],
+                vec![("3", 1, "y"), ("5", 1, "x"), ("7", 1, "x")],
+            ],
+        )
+        .await;
+        let diff = left
+            .diff(right, |key| key.replacen("a/", "b/", 1))
+            .map_ok(summary)
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap();
+        let expected = vec![
+            ("right", "b/0"),
+            ("left", "a/1"),
+            ("size", "b/2"),
+            ("etag", "b/3"),
+            ("left", "a/6"),
+            ("right", "b/7"),
+            ("left", "a/8"),
+        ];
+        assert_eq!(
+            diff,
+            expected
+                .into_iter()
+                .map(|(kind, key)| (kind, key.to_owned()))
+                .collect::<Vec<_>>()
+        );
+
+        let unsorted = listing("a/", vec![vec![("2", 1, "x"), ("1", 1, "x")]]).await;
+        let right = listing("a/", vec![]).await;
+        let result = unsorted
+            .diff(right, |key| key.to_owned())
+            .try_collect::<Vec<_>>()
+            .await;
+        assert!(matches!(result, Err(Error::UnsortedListing { .. })));
+    }
+}
@@ -0,0 +1,398 @@
+//! Server-side encryption settings, applied to every request that reads or writes object data.
+use aws_sdk_s3::operation::copy_object::builders::CopyObjectFluentBuilder;
+use aws_sdk_s3::operation::create_multipart_upload::builders::CreateMultipartUploadFluentBuilder;
+use aws_sdk_s3::operation::get_object::builders::GetObjectFluentBuilder;
+use aws_sdk_s3::operation::head_object::builders::HeadObjectFluentBuilder;
+use aws_sdk_s3::operation::put_object::builders::PutObjectFluentBuilder;
+use aws_sdk_s3::operation::upload_part_copy::builders::UploadPartCopyFluentBuilder;
+use aws_sdk_s3::types::ServerSideEncryption;
+use md5::{Digest, Md5};
+use std::fmt;
+
+/// The only algorithm S3 accepts for customer-provided keys.
+const SSE_CUSTOMER_ALGORITHM: &str = "AES256";
+
+/// Server-side encryption of the objects that `S3Algo` reads and writes.
+///
+/// Set it for all operations with `S3Algo::with_encryption`, or for a single listing with
+/// `ListObjects::with_encryption`.
+///
+/// SSE-S3 and SSE-KMS only affect requests that write objects (PUT and the destination of a
+/// copy); S3 decrypts transparently on read. SSE-C on the other hand requires the key on every
+/// request that touches object data: GET, PUT, HEAD and both the source and destination of a copy.
+#[derive(Clone, Debug, Default)]
+pub enum Encryption {
+    /// Send no encryption headers - the default encryption of the bucket applies.
+    #[default]
+    None,
+    /// SSE-S3: keys managed by S3 (`AES256`).
+    S3Managed,
+    /// SSE-KMS: keys managed by AWS KMS.
+    Kms {
+        /// The KMS key to use. If `None`, the AWS managed key is used.
+        key_id: Option<String>,
+        /// Base64-encoded JSON with the encryption context.
+        context: Option<String>,
+        /// Use an S3 Bucket Key for the objects.
+        bucket_key_enabled: Option<bool>,
+    },
+    /// SSE-C: keys provided by the customer on each request.
+    CustomerKey(CustomerKey),
+}
+
+impl Encryption {
+    /// Apply the encryption settings to a PutObject request.
+    pub fn put_object(&self, request: PutObjectFluentBuilder) -> PutObjectFluentBuilder {
+        match self {
+            Self::None => request,
+            Self::S3Managed => request.server_side_encryption(ServerSideEncryption::Aes256),
+            Self::Kms {
+                key_id,
+                context,
+                bucket_key_enabled,
+            } => request
+                .server_side_encryption(ServerSideEncryption::AwsKms)
+                .set_ssekms_key_id(key_id.clone())
+                .set_ssekms_encryption_context(context.clone())
+                .set_bucket_key_enabled(*bucket_key_enabled),
+            Self::CustomerKey(key) => request
+                .sse_customer_algorithm(SSE_CUSTOMER_ALGORITHM)
+                .sse_customer_key(key.key.clone())
+                .sse_customer_key_md5(key.key_md5.clone()),
+        }
+    }
+
+    /// Apply the encryption settings to a GetObject request.
+    pub fn get_object(&self, request: GetObjectFluentBuilder) -> GetObjectFluentBuilder {
+        match self {
+            Self::CustomerKey(key) => request
+                .sse_customer_algorithm(SSE_CUSTOMER_ALGORITHM)
+                .sse_customer_key(key.key.clone())
+                .sse_customer_key_md5(key.key_md5.clone()),
+            _ => request,
+        }
+    }
+
+    /// Apply the encryption settings to a HeadObject request.
+    pub fn head_object(&self, request: HeadObjectFluentBuilder) -> HeadObjectFluentBuilder {
+        match self {
+            Self::CustomerKey(key) => request
+                .sse_customer_algorithm(SSE_CUSTOMER_ALGORITHM)
+                .sse_customer_key(key.key.clone())
+                .sse_customer_key_md5(key.key_md5.clone()),
+            _ => request,
+        }
+    }
+
+    /// Apply the encryption settings to the destination of a CopyObject request.
+    pub fn copy_object(&self, request: CopyObjectFluentBuilder) -> CopyObjectFluentBuilder {
+        match self {
+            Self::None => request,
+            Self::S3Managed => request.server_side_encryption(ServerSideEncryption::Aes256),
+            Self::Kms {
+                key_id,
+                context,
+                bucket_key_enabled,
+            } => request
+                .server_side_encryption(ServerSideEncryption::AwsKms)
+                .set_ssekms_key_id(key_id.clone())
+                .set_ssekms_encryption_context(context.clone())
+                .set_bucket_key_enabled(*bucket_key_enabled),
+            Self::CustomerKey(key) => request
+                .sse_customer_algorithm(SSE_CUSTOMER_ALGORITHM)
+                .sse_customer_key(key.key.clone())
+                .sse_customer_key_md5(key.key_md5.clone()),
+        }
+    }
+
+    /// Apply the encryption settings to the source of a CopyObject request. Only SSE-C needs
+    /// this.
+    pub fn copy_object_source(&self, request: CopyObjectFluentBuilder) -> CopyObjectFluentBuilder {
+        match self {
+            Self::CustomerKey(key) => request
+                .copy_source_sse_customer_algorithm(SSE_CUSTOMER_ALGORITHM)
+                .copy_source_sse_customer_key(key.key.clone())
+                .copy_source_sse_customer_key_md5(key.key_md5.clone()),
+            _ => request,
+        }
+    }
+
+    /// Apply the encryption settings to a CreateMultipartUpload request.
+    pub fn create_multipart_upload(
+        &self,
+        request: CreateMultipartUploadFluentBuilder,
+    ) -> CreateMultipartUploadFluentBuilder {
+        match self {
+            Self::None => request,
+            Self::S3Managed => request.server_side_encryption(ServerSideEncryption::Aes256),
+            Self::Kms {
+                key_id,
+                context,
+                bucket_key_enabled,
+            } => request
+                .server_side_encryption(ServerSideEncryption::AwsKms)
+                .set_ssekms_key_id(key_id.clone())
+                .set_ssekms_encryption_context(context.clone())
+                .set_bucket_key_enabled(*bucket_key_enabled),
+            Self::CustomerKey(key) => request
+                .sse_customer_algorithm(SSE_CUSTOMER_ALGORITHM)
+                .sse_customer_key(key.key.clone())
+                .sse_customer_key_md5(key.key_md5.clone()),
+        }
+    }
+
+    /// Apply the encryption settings to both the source and the destination of an UploadPartCopy
+    /// request. Only SSE-C needs this; the other settings are given to CreateMultipartUpload.
+    pub fn upload_part_copy(
+        &self,
+        request: UploadPartCopyFluentBuilder,
+    ) -> UploadPartCopyFluentBuilder {
+        match self {
+            Self::CustomerKey(key) => request
+                .sse_customer_algorithm(SSE_CUSTOMER_ALGORITHM)
+                .sse_customer_key(key.key.clone())
+                .sse_customer_key_md5(key.key_md5.clone())
+                .copy_source_sse_customer_algorithm(SSE_CUSTOMER_ALGORITHM)
+                .copy_source_sse_customer_key(key.key.clone())
+                .copy_source_sse_customer_key_md5(key.key_md5.clone()),
+            _ => request,
+        }
+    }
+}
+
+/// A 256-bit key for SSE-C, stored in the base64 encoding that S3 expects.
+#[derive(Clone, PartialEq, Eq)]
+pub struct CustomerKey {
+    key: String,
+    key_md5: String,
+}
+
+impl CustomerKey {
+    pub fn new(key: [u8; 32]) -> Self {
+        Self {
+            key: aws_smithy_types::base64::encode(key),
+            key_md5: aws_smithy_types::base64::encode(Md5::digest(key)),
+        }
+    }
+    /// Base64-encoded MD5 digest of the key, which S3 uses to verify the key.
+    pub fn key_md5(&self) -> &str {
+        &self.key_md5
+    }
+}
+
+impl fmt::Debug for CustomerKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Never print the key itself
+        f.debug_struct("CustomerKey")
+            .field("key_md5", &self.key_md5)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use aws_sdk_s3::Client;
+
+    fn client() -> Client {
+        Client::from_conf(aws_sdk_s3::Config::builder().build())
+    }
+
+    fn customer_key() -> CustomerKey {
+        CustomerKey::new([7u8; 32])
+    }
+
+    #[test]
+    fn customer_key_encoding() {
+        let mut raw = [0u8; 32];
+        for (i, byte) in raw.iter_mut().enumerate() {
+            *byte = i as u8;
+        }
+        let key = CustomerKey::new(raw);
+        assert_eq!(key.key, "AAECAwQFBgcICQoLDA0ODxAREhMUFRYXGBkaGxwdHh8=");
+        assert_eq!(key.key_md5(), "tP/LI3N87DFaSk0aoqYgzg==");
+        assert!(!format!("{:?}", key).contains(&key.key));
+    }
+
+    #[test]
+    fn no_encryption() {
+        let s3 = client();
+        let put = Encryption::None.put_object(s3.put_object());
+        assert_eq!(put.get_server_side_encryption(), &None);
+        assert_eq!(put.get_sse_customer_key(), &None);
+        let copy =
+            Encryption::None.copy_object_source(Encryption::None.copy_object(s3.copy_object()));
+        assert_eq!(copy.get_server_side_encryption(), &None);
+        assert_eq!(copy.get_copy_source_sse_customer_key(), &None);
+    }
+
+    #[test]
+    fn s3_managed() {
+        let s3 = client();
+        let encryption = Encryption::S3Managed;
+        let put = encryption.put_object(s3.put_object());
+        assert_eq!(
+            put.get_server_side_encryption(),
+            &Some(ServerSideEncryption::Aes256)
+        );
+        let copy = encryption.copy_object_source(encryption.copy_object(s3.copy_object()));
+        assert_eq!(
+            copy.get_server_side_encryption(),
+            &Some(ServerSideEncryption::Aes256)
+        );
+        assert_eq!(copy.get_copy_source_sse_customer_key(), &None);
+        // S3 decrypts on read without any headers
+        let get = encryption.get_object(s3.get_object());
+        assert_eq!(get.get_sse_customer_key(), &None);
+        let head = encryption.head_object(s3.head_object());
+        assert_eq!(head.get_sse_customer_key(), &None);
+    }
+
+    #[test]
+    fn kms() {
+        let s3 = client();
+        let encryption = Encryption::Kms {
+            key_id: Some("key".into()),
+            context: Some("e30=".into()),
+            bucket_key_enabled: Some(true),
+        };
+        let put = encryption.put_object(s3.put_object());
+        assert_eq!(
+            put.get_server_side_encryption(),
+            &Some(ServerSideEncryption::AwsKms)
+        );
+        assert_eq!(put.get_ssekms_key_id().as_deref(), Some("key"));
+        assert_eq!(put.get_ssekms_encryption_context().as_deref(), Some("e30="));
+        assert_eq!(put.get_bucket_key_enabled(), &Some(true));
+        let copy = encryption.copy_object_source(encryption.copy_object(s3.copy_object()));
+        assert_eq!(
+            copy.get_server_side_encryption(),
+            &Some(ServerSideEncryption::AwsKms)
+        );
+        assert_eq!(copy.get_ssekms_key_id().as_deref(), Some("key"));
+        assert_eq!(copy.get_bucket_key_enabled(), &Some(true));
+        assert_eq!(copy.get_copy_source_sse_customer_key(), &None);
+        let get = encryption.get_object(s3.get_object());
+        assert_eq!(get.get_sse_customer_key(), &None);
+    }
+
+    #[test]
+    fn customer_key_on_every_request() {
+        let s3 = client();
+        let key = customer_key();
+        let encryption = Encryption::CustomerKey(key.clone());
+        let expected = (
+            Some(SSE_CUSTOMER_ALGORITHM.to_owned()),
+            Some(key.key.clone()),
+            Some(key.key_md5.clone()),
+        );
+
+        let put = encryption.put_object(s3.put_object());
+        assert_eq!(put.get_server_side_encryption(), &None);
+        assert_eq!(
+            (
+                put.get_sse_customer_algorithm().clone(),
+                put.get_sse_customer_key().clone(),
+                put.get_sse_customer_key_md5().clone()
+            ),
+            expected
+        );
+        let get = encryption.get_object(s3.get_object());
+        assert_eq!(
+            (
+                get.get_sse_customer_algorithm().clone(),
+                get.get_sse_customer_key().clone(),
+                get.get_sse_customer_key_md5().clone()
+            ),
+            expected
+        );
+        let head = encryption.head_object(s3.head_object());
+        assert_eq!(
+            (
+                head.get_sse_customer_algorithm().clone(),
+                head.get_sse_customer_key().clone(),
+                head.get_sse_customer_key_md5().clone()
+            ),
+            expected
+        );
+
+        // Both the destination and the source of a copy
+        let copy = encryption.copy_object(s3.copy_object());
+        assert_eq!(copy.get_copy_source_sse_customer_key(), &None);
+        let copy = encryption.copy_object_source(copy);
+        assert_eq!(
+            (
+                copy.get_sse_customer_algorithm().clone(),
+                copy.get_sse_customer_key().clone(),
+                copy.get_sse_customer_key_md5().clone()
+            ),
+            expected
+        );
+        assert_eq!(
+            (
+                copy.get_copy_source_sse_customer_algorithm().clone(),
+                copy.get_copy_source_sse_customer_key().clone(),
+                copy.get_copy_source_sse_customer_key_md5().clone()
+            ),
+            expected
+        );
+    }
+
+    #[test]
+    fn multipart_copy() {
+        let s3 = client();
+        let create = Encryption::S3Managed.create_multipart_upload(s3.create_multipart_upload());
+        assert_eq!(
+            create.get_server_side_encryption(),
+            &Some(ServerSideEncryption::Aes256)
+        );
+        let part = Encryption::S3Managed.upload_part_copy(s3.upload_part_copy());
+        assert_eq!(part.get_copy_source_sse_customer_key(), &None);
+
+        let encryption = Encryption::Kms {
+            key_id: Some("key".into()),
+            context: None,
+            bucket_key_enabled: None,
+        };
+        let create = encryption.create_multipart_upload(s3.create_multipart_upload());
+        assert_eq!(
+            create.get_server_side_encryption(),
+            &Some(ServerSideEncryption::AwsKms)
+        );
+        assert_eq!(create.get_ssekms_key_id().as_deref(), Some("key"));
+        let part = encryption.upload_part_copy(s3.upload_part_copy());
+        assert_eq!(part.get_sse_customer_key(), &None);
+
+        let key = customer_key();
+        let encryption = Encryption::CustomerKey(key.clone());
+        let create = encryption.create_multipart_upload(s3.create_multipart_upload());
+        assert_eq!(create.get_server_side_encryption(), &None);
+        assert_eq!(create.get_sse_customer_key().as_deref(), Some(&key.key[..]));
+        // Both the destination and the source of each part
+        let part = encryption.upload_part_copy(s3.upload_part_copy());
+        assert_eq!(
+            (
+                part.get_sse_customer_algorithm().as_deref(),
+                part.get_sse_customer_key().as_deref(),
+                part.get_sse_customer_key_md5().as_deref()
+            ),
+            (
+                Some(SSE_CUSTOMER_ALGORITHM),
+                Some(&key.key[..]),
+                Some(&key.key_md5[..])
+            )
+        );
+        assert_eq!(
+            (
+                part.get_copy_source_sse_customer_algorithm().as_deref(),
+                part.get_copy_source_sse_customer_key().as_deref(),
+                part.get_copy_source_sse_customer_key_md5().as_deref()
+            ),
+            (
+                Some(SSE_CUSTOMER_ALGORITHM),
+                Some(&key.key[..]),
+                Some(&key.key_md5[..])
+            )
+        );
+    }
+}
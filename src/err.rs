@@ -3,6 +3,7 @@ use aws_sdk_s3::operation::copy_object::CopyObjectError;
 use aws_sdk_s3::operation::delete_object::DeleteObjectError;
 use aws_sdk_s3::operation::delete_objects::DeleteObjectsError;
 use aws_sdk_s3::operation::get_object::GetObjectError;
+use aws_sdk_s3::operation::list_object_versions::ListObjectVersionsError;
 use aws_sdk_s3::operation::list_objects_v2::ListObjectsV2Error;
 use aws_sdk_s3::operation::put_object::PutObjectError;
 use snafu::{Backtrace, Snafu};
@@ -31,6 +32,10 @@ pub enum Error {
     ListObjectsV2 {
         source: SdkError<ListObjectsV2Error>,
     },
+    #[snafu(display("Error listing object versions in S3: {:?}", source))]
+    ListObjectVersions {
+        source: SdkError<ListObjectVersionsError>,
+    },
     #[snafu(display("Error deleting objects in S3: {:?}", source))]
     DeleteObjects {
         source: SdkError<DeleteObjectsError>,
@@ -47,6 +52,16 @@ pub enum Error {
         bucket: String,
         source: SdkError<GetObjectError>,
     },
+    #[snafu(display("Invalid glob '{}': {}", glob, source))]
+    Glob {
+        glob: String,
+        source: globset::Error,
+    },
+    #[snafu(display("Invalid regex '{}': {}", regex, source))]
+    Regex {
+        regex: String,
+        source: regex::Error,
+    },
     #[snafu(display("IO error: {}", source))]
     TokioIo {
         source: tokio::io::Error,
@@ -59,6 +74,59 @@ pub enum Error {
     MissingKeyOrSize,
     #[snafu(display("Downloading objects: missing content_length property"))]
     MissingContentLength,
+    #[snafu(display("Restoring objects: missing key or version id property"))]
+    MissingKeyOrVersion,
+    #[snafu(display("Can not map '{}' between path and key: {}", name, reason))]
+    InvalidName {
+        name: String,
+        reason: String,
+    },
+    #[snafu(display("Invalid date '{}': {}", date, source))]
+    InvalidDate {
+        date: String,
+        source: aws_smithy_types::date_time::DateTimeParseError,
+    },
+    #[snafu(display("Listing is not in lexicographic order at key '{}'", key))]
+    UnsortedListing {
+        key: String,
+    },
+    #[snafu(display("Invalid S3 Inventory report: {}", reason))]
+    InvalidInventory {
+        reason: String,
+    },
+    #[snafu(display("Key '{}' is not in the shard index", key))]
+    MissingIndexEntry {
+        key: String,
+    },
+    #[snafu(display(
+        "Object '{}' can not be written to a tar archive as '{}', which is not a relative path without '..'",
+        key,
+        path
+    ))]
+    TarPath {
+        key: String,
+        path: String,
+    },
+    #[snafu(display(
+        "The body of '{}' is read as it is uploaded, and can not be read again",
+        key
+    ))]
+    BodyConsumed {
+        key: String,
+    },
+    #[snafu(display("Invalid presigning configuration: {}", source))]
+    Presigning {
+        source: aws_sdk_s3::presigning::PresigningConfigError,
+    },
+    #[snafu(display("Object '{}' is archived, and not being restored", key))]
+    NotRestoring {
+        key: String,
+    },
+    #[snafu(display("Multipart copy to '{}': {}", key, reason))]
+    MultipartCopy {
+        key: String,
+        reason: String,
+    },
 
     // AWS SDK Errors
     #[snafu(display("S3 'put object' error on key '{}': {}", key, source))]
@@ -102,6 +170,24 @@ where
     }
 }
 
+#[cfg(feature = "serde1")]
+impl From<serde_json::Error> for Error {
+    fn from(err: serde_json::Error) -> Self {
+        Self::AnyError {
+            source: Box::new(err),
+        }
+    }
+}
+
+#[cfg(feature = "serde1")]
+impl From<csv::Error> for Error {
+    fn from(err: csv::Error) -> Self {
+        Self::AnyError {
+            source: Box::new(err),
+        }
+    }
+}
+
 impl From<aws_smithy_http::byte_stream::error::Error> for Error {
     fn from(err: aws_smithy_http::byte_stream::error::Error) -> Self {
         Self::AnyError {
@@ -0,0 +1,451 @@
+//! Restoring archived objects (GLACIER and DEEP_ARCHIVE) and waiting until they can be read.
+use super::*;
+use aws_sdk_s3::error::ProvideErrorMetadata;
+use aws_sdk_s3::operation::head_object::HeadObjectOutput;
+use aws_sdk_s3::operation::list_objects_v2::ListObjectsV2Output;
+use aws_sdk_s3::types::{
+    GlacierJobParameters, Object, ObjectStorageClass, RestoreRequest, StorageClass, Tier,
+};
+
+/// Whether a listed object must be restored before it can be read.
+pub(crate) fn is_archived(obj: &Object) -> bool {
+    matches!(
+        obj.storage_class,
+        Some(ObjectStorageClass::Glacier) | Some(ObjectStorageClass::DeepArchive)
+    )
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum RestoreStatus {
+    /// Not archived, or a restored copy is available.
+    Readable,
+    /// A restore is in progress.
+    Ongoing,
+    /// Archived, and no restore was requested.
+    Archived,
+}
+
+/// The restore status of an object from its HeadObject response, whose `x-amz-restore` header is
+/// `ongoing-request="true"` during a restore, and for example
+/// `ongoing-request="false", expiry-date="Fri, 21 Dec 2012 00:00:00 GMT"` after.
+fn restore_status(head: &HeadObjectOutput) -> RestoreStatus {
+    match head.restore.as_deref() {
+        Some(restore) if restore.contains("ongoing-request=\"true\"") => RestoreStatus::Ongoing,
+        Some(_) => RestoreStatus::Readable,
+        None => match head.storage_class {
+            Some(StorageClass::Glacier) | Some(StorageClass::DeepArchive) => {
+                RestoreStatus::Archived
+            }
+            _ => RestoreStatus::Readable,
+        },
+    }
+}
+
+/// The maximum number of objects in a page, as in a listing.
+const MAX_PAGE_SIZE: usize = 1000;
+/// The interval between polls grows up to this many times the poll interval.
+const MAX_BACKOFF: u32 = 16;
+
+struct PollState {
+    /// Objects that are still being restored
+    pending: Vec<Object>,
+    /// Objects that are readable but not yet yielded
+    readable: Vec<Object>,
+    /// How long to wait before the next poll, `None` before the first
+    interval: Option<Duration>,
+}
+
+/// Poll `head` for each of `objects`, and yield pages (of at most 1000 objects) of the objects
+/// that have become readable. Objects that are archived without a restore in progress fail with
+/// `Error::NotRestoring`.
+///
+/// Polls start `poll_interval` apart. The interval doubles after each poll where no object
+/// became readable, up to `MAX_BACKOFF` times `poll_interval`, and is reset when one does.
+fn poll_restored<H, F>(
+    objects: Vec<Object>,
+    poll_interval: Duration,
+    parallelization: usize,
+    head: H,
+) -> impl Stream<Item = Result<ListObjectsV2Output, Error>> + Send
+where
+    H: Fn(String) -> F + Clone + Send + Sync + 'static,
+    F: Future<Output = Result<HeadObjectOutput, Error>> + Send + 'static,
+{
+    let state = PollState {
+        pending: objects,
+        readable: Vec::new(),
+        interval: None,
+    };
+    stream::try_unfold(state, move |mut state| {
+        let head = head.clone();
+        async move {
+            loop {
+                if !state.readable.is_empty() {
+                    let n = state.readable.len().min(MAX_PAGE_SIZE);
+                    let objects = state.readable.drain(..n).collect::<Vec<_>>();
+                    let page = ListObjectsV2Output::builder()
+                        .key_count(objects.len() as i32)
+                        .set_contents(Some(objects))
+                        .build();
+                    return Ok(Some((page, state)));
+                }
+                if state.pending.is_empty() {
+                    return Ok(None);
+                }
+                if let Some(interval) = state.interval {
+                    tokio::time::sleep(interval).await;
+                }
+                let keys = state
+                    .pending
+                    .iter()
+                    .map(|obj| obj.key.clone().unwrap_or_default())
+                    .collect::<Vec<_>>();
+                let statuses = stream::iter(keys)
+                    .map(|key| {
+                        let head = head.clone();
+                        async move {
+                            match restore_status(&head(key.clone()).await?) {
+                                RestoreStatus::Archived => Err(Error::NotRestoring { key }),
+                                status => Ok(status),
+                            }
+                        }
+                    })
+                    .buffered(parallelization)
+                    .try_collect::<Vec<_>>()
+                    .await?;
+                let (readable, ongoing): (Vec<_>, Vec<_>) = std::mem::take(&mut state.pending)
+                    .into_iter()
+                    .zip(statuses)
+                    .partition(|(_, status)| *status == RestoreStatus::Readable);
+                state.pending = ongoing.into_iter().map(|(obj, _)| obj).collect();
+                state.readable = readable.into_iter().map(|(obj, _)| obj).collect();
+                state.interval = Some(match state.interval {
+                    Some(interval) if state.readable.is_empty() => {
+                        (interval * 2).min(poll_interval * MAX_BACKOFF)
+                    }
+                    _ => poll_interval,
+                });
+            }
+        }
+    })
+}
+
+impl<S> ListObjects<S>
+where
+    S: Stream<Item = Result<ListObjectsV2Output, Error>> + Sized + Send + 'static,
+{
+    /// Request a restore of every listed object in GLACIER or DEEP_ARCHIVE, with the retrieval
+    /// `tier`, for a restored copy that is kept for `days` days. Other objects are skipped.
+    ///
+    /// Objects with a restore already in progress count as requested. Up to
+    /// `copy_parallelization` requests are sent at the same time, and `progress` is called with
+    /// the `RequestReport` of each request, where `size` is 1.
+    ///
+    /// Returns the archived objects, to wait for with `S3Algo::wait_restored`. Objects in other
+    /// storage classes are not kept, so the memory usage is bounded by the number of archived
+    /// objects.
+    pub fn request_restore<P, F>(
+        self,
+        days: i32,
+        tier: Tier,
+        progress: P,
+    ) -> impl Future<Output = Result<Vec<Object>, Error>>
+    where
+        P: Fn(RequestReport) -> F + Clone + Send + Sync + 'static,
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let (s3, bucket) = (self.s3.clone(), self.bucket.clone());
+        let timeout = Arc::new(Mutex::new(TimeoutState::new(
+            self.config.algorithm.clone(),
+            self.config.delete_requests.clone(),
+        )));
+        let n_retries = self.config.algorithm.n_retries;
+        let parallelization = self.config.copy_parallelization;
+        let request = RestoreRequest::builder()
+            .days(days)
+            .glacier_job_parameters(GlacierJobParameters::builder().tier(tier).build())
+            .build();
+        self.flatten()
+            .try_filter(|obj| future::ready(is_archived(obj)))
+            .map_ok(move |obj| {
+                let (s3, bucket, request, timeout, progress) = (
+                    s3.clone(),
+                    bucket.clone(),
+                    request.clone(),
+                    timeout.clone(),
+                    progress.clone(),
+                );
+                async move {
+                    let key = obj.key.clone().ok_or(Error::MissingKeyOrSize)?;
+                    let (report, _) = s3_request(
+                        move || {
+                            let (s3, bucket, key, request) =
+                                (s3.clone(), bucket.clone(), key.clone(), request.clone());
+                            async move {
+                                Ok((
+                                    async move {
+                                        let result = s3
+                                            .restore_object()
+                                            .bucket(bucket)
+                                            .key(key)
+                                            .restore_request(request)
+                                            .send()
+                                            .await;
+                                        match result {
+                                            Ok(_) => Ok(()),
+                                            Err(e)
+                                                if e.code() == Some("RestoreAlreadyInProgress") =>
+                                            {
+                                                Ok(())
+                                            }
+                                            Err(e) => Err(Error::from(e)),
+                                        }
+                                    },
+                                    1,
+                                ))
+                            }
+                        },
+                        |_, size| size,
+                        n_retries,
+                        timeout.clone(),
+                    )
+                    .await?;
+                    timeout.lock().await.update(&report);
+                    progress(report).await;
+                    Ok::<_, Error>(obj)
+                }
+            })
+            .try_buffer_unordered(parallelization)
+            .try_collect()
+    }
+}
+
+impl S3Algo {
+    /// Wait until `objects` of `bucket` can be read, typically the objects returned by
+    /// `ListObjects::request_restore`. At each poll, a HeadObject request is sent for each object
+    /// that is still being restored. Polls start `poll_interval` apart, and back off up to 16 times
+    /// that while no object becomes readable, since restores take hours.
+    ///
+    /// The returned listing yields the objects as they become readable, in pages of at most 1000
+    /// objects, so they can be downloaded right away with for example
+    /// `ListObjects::download_all_stream`, or deleted with `ListObjects::delete_all`. Objects
+    /// that are not archived are readable at the first poll. An archived object without a restore
+    /// in progress fails with `Error::NotRestoring`, since it would never become readable.
+    pub fn wait_restored(
+        &self,
+        bucket: String,
+        objects: Vec<Object>,
+        poll_interval: Duration,
+    ) -> ListObjects<impl Stream<Item = Result<ListObjectsV2Output, Error>> + Sized + Send> {
+        let (s3, encryption, bucket2) = (self.s3.clone(), self.encryption.clone(), bucket.clone());
+        let timeout = Arc::new(Mutex::new(TimeoutState::new(
+            self.config.algorithm.clone(),
+            self.config.delete_requests.clone(),
+        )));
+        let n_retries = self.config.algorithm.n_retries;
+        let head = move |key: String| {
+            let (s3, encryption, bucket, timeout) = (
+                s3.clone(),
+                encryption.clone(),
+                bucket2.clone(),
+                timeout.clone(),
+            );
+            async move {
+                let (report, head) = s3_request(
+                    move || {
+                        let (s3, encryption, bucket, key) =
+                            (s3.clone(), encryption.clone(), bucket.clone(), key.clone());
+                        async move {
+                            Ok((
+                                async move {
+                                    Ok::<_, Error>(
+                                        encryption
+                                            .head_object(s3.head_object().bucket(bucket).key(key))
+                                            .send()
+                                            .await?,
+                                    )
+                                },
+                                1,
+                            ))
+                        }
+                    },
+                    |_, size| size,
+                    n_retries,
+                    timeout.clone(),
+                )
+                .await?;
+                timeout.lock().await.update(&report);
+                Ok::<_, Error>(head)
+            }
+        };
+        ListObjects {
+            s3: self.s3.clone(),
+            config: self.config.clone(),
+            bucket,
+            prefix: String::new(),
+            encryption: self.encryption.clone(),
+            stream: poll_restored(
+                objects,
+                poll_interval,
+                self.config.copy_parallelization,
+                head,
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::HashMap;
+    use std::sync::Mutex as SyncMutex;
+
+    fn head(storage_class: Option<StorageClass>, restore: Option<&str>) -> HeadObjectOutput {
+        HeadObjectOutput::builder()
+            .set_storage_class(storage_class)
+            .set_restore(restore.map(String::from))
+            .build()
+    }
+
+    #[test]
+    fn parse_restore_status() {
+        let glacier = Some(StorageClass::Glacier);
+        assert_eq!(
+            restore_status(&head(glacier.clone(), Some("ongoing-request=\"true\""))),
+            RestoreStatus::Ongoing
+        );
+        assert_eq!(
+            restore_status(&head(
+                glacier.clone(),
+                Some("ongoing-request=\"false\", expiry-date=\"Fri, 21 Dec 2012 00:00:00 GMT\"")
+            )),
+            RestoreStatus::Readable
+        );
+        assert_eq!(
+            restore_status(&head(glacier, None)),
+            RestoreStatus::Archived
+        );
+        assert_eq!(
+            restore_status(&head(Some(StorageClass::DeepArchive), None)),
+            RestoreStatus::Archived
+        );
+        assert_eq!(restore_status(&head(None, None)), RestoreStatus::Readable);
+    }
+
+    /// A stand-in for S3 that reports a restore in progress for the first `polls` HeadObject
+    /// requests of each key.
+    fn simulated_head(
+        polls: HashMap<&'static str, usize>,
+    ) -> impl Fn(String) -> future::Ready<Result<HeadObjectOutput, Error>> + Clone {
+        let polls = Arc::new(SyncMutex::new(polls));
+        move |key: String| {
+            let mut polls = polls.lock().unwrap();
+            let remaining = polls.get_mut(key.as_str()).expect("unknown key");
+            let restore = if *remaining == 0 {
+                "ongoing-request=\"false\", expiry-date=\"Fri, 21 Dec 2012 00:00:00 GMT\""
+            } else {
+                *remaining -= 1;
+                "ongoing-request=\"true\""
+            };
+            future::ready(Ok(head(Some(StorageClass::Glacier), Some(restore))))
+        }
+    }
+
+    fn objects(keys: &[&str]) -> Vec<Object> {
+        keys.iter()
+            .map(|key| {
+                Object::builder()
+                    .key(*key)
+                    .storage_class(ObjectStorageClass::Glacier)
+                    .build()
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn poll_until_restored() {
+        let polls = vec![("a", 0), ("b", 2), ("c", 1), ("d", 2)]
+            .into_iter()
+            .collect();
+        let pages = poll_restored(
+            objects(&["a", "b", "c", "d"]),
+            Duration::from_millis(1),
+            2,
+            simulated_head(polls),
+        )
+        .map_ok(|page| {
+            page.contents
+                .unwrap()
+                .into_iter()
+                .map(|obj| obj.key.unwrap())
+                .collect::<Vec<_>>()
+        })
+        .try_collect::<Vec<_>>()
+        .await
+        .unwrap();
+        assert_eq!(pages, vec![vec!["a"], vec!["c"], vec!["b", "d"]]);
+    }
+
+    #[tokio::test]
+    async fn poll_in_pages() {
+        let keys = (0..2500).map(|i| i.to_string()).collect::<Vec<_>>();
+        let keys = keys.iter().map(String::as_str).collect::<Vec<_>>();
+        let readable = |_| {
+            future::ready(Ok(head(
+                Some(StorageClass::Glacier),
+                Some("ongoing-request=\"false\""),
+            )))
+        };
+        let page_sizes = poll_restored(objects(&keys), Duration::from_millis(1), 10, readable)
+            .map_ok(|page| page.contents.unwrap().len())
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap();
+        assert_eq!(page_sizes, vec![1000, 1000, 500]);
+    }
+
+    #[tokio::test]
+    async fn poll_not_restoring() {
+        let archived = |_| future::ready(Ok(head(Some(StorageClass::DeepArchive), None)));
+        let result = poll_restored(objects(&["a"]), Duration::from_millis(1), 2, archived)
+            .try_collect::<Vec<_>>()
+            .await;
+        assert!(matches!(result, Err(Error::NotRestoring { .. })));
+    }
+
+    #[tokio::test]
+    async fn test_s3_restore_skips_unarchived() {
+        let algo = S3Algo::new(testing_sdk_client().await);
+        let key = crate::test::rand_string(14);
+        algo.s3
+            .put_object()
+            .bucket("test-bucket")
+            .key(&key)
+            .body(vec![1, 2, 3].into())
+            .send()
+            .await
+            .unwrap();
+
+        let objects = algo
+            .list_prefix("test-bucket".into(), Some(key.clone()))
+            .request_restore(1, Tier::Bulk, |_| async {})
+            .await
+            .unwrap();
+        assert!(objects.is_empty());
+
+        // Objects that are not archived are readable right away
+        let listed = algo
+            .list_prefix("test-bucket".into(), Some(key.clone()))
+            .flatten()
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap();
+        let downloaded = algo
+            .wait_restored("test-bucket".into(), listed, Duration::from_secs(1))
+            .download_all_to_vec()
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap();
+        assert_eq!(downloaded, vec![(key, vec![1, 2, 3])]);
+    }
+}
@@ -0,0 +1,375 @@
+//! Listing objects from S3 Inventory reports instead of with ListObjectsV2.
+use super::*;
+use crate::list_actions::download_object;
+use aws_sdk_s3::operation::list_objects_v2::ListObjectsV2Output;
+use aws_sdk_s3::primitives::{DateTime, DateTimeFormat};
+use aws_sdk_s3::types::{Object, ObjectStorageClass};
+use percent_encoding::percent_decode_str;
+use serde::Deserialize;
+use std::io::{Cursor, Read};
+
+/// The `manifest.json` of an inventory report. Only the fields we need.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct InventoryManifest {
+    source_bucket: String,
+    /// ARN of the bucket that holds the data files, `arn:aws:s3:::{bucket}`.
+    destination_bucket: String,
+    file_format: String,
+    /// Comma-separated names of the CSV columns, such as `Bucket, Key, Size`.
+    file_schema: String,
+    files: Vec<InventoryFile>,
+}
+
+#[derive(Debug, Deserialize)]
+struct InventoryFile {
+    key: String,
+    size: Option<i64>,
+}
+
+impl InventoryManifest {
+    fn data_bucket(&self) -> &str {
+        self.destination_bucket
+            .strip_prefix("arn:aws:s3:::")
+            .unwrap_or(&self.destination_bucket)
+    }
+}
+
+/// Positions of the columns we use in the CSV data files.
+#[derive(Clone, Debug)]
+struct Columns {
+    key: usize,
+    size: Option<usize>,
+    last_modified: Option<usize>,
+    etag: Option<usize>,
+    storage_class: Option<usize>,
+    is_latest: Option<usize>,
+    is_delete_marker: Option<usize>,
+}
+
+impl Columns {
+    fn from_schema(schema: &str) -> Result<Self, Error> {
+        let names = schema.split(',').map(str::trim).collect::<Vec<_>>();
+        let column = |name| names.iter().position(|column| *column == name);
+        Ok(Self {
+            key: column("Key").ok_or_else(|| Error::InvalidInventory {
+                reason: format!("no `Key` in the file schema `{}`", schema),
+            })?,
+            size: column("Size"),
+            last_modified: column("LastModifiedDate"),
+            etag: column("ETag"),
+            storage_class: column("StorageClass"),
+            is_latest: column("IsLatest"),
+            is_delete_marker: column("IsDeleteMarker"),
+        })
+    }
+
+    /// The current object of a row, or `None` for old versions and delete markers.
+    fn object(&self, row: &csv::StringRecord) -> Result<Option<Object>, Error> {
+        let field = |column: Option<usize>| {
+            column
+                .and_then(|column| row.get(column))
+                .filter(|value| !value.is_empty())
+        };
+        if field(self.is_latest) == Some("false") || field(self.is_delete_marker) == Some("true") {
+            return Ok(None);
+        }
+        let key = field(Some(self.key)).ok_or_else(|| Error::InvalidInventory {
+            reason: format!("row without key: {:?}", row),
+        })?;
+        // Keys are URL-encoded in CSV reports
+        let key = percent_decode_str(&key.replace('+', " "))
+            .decode_utf8_lossy()
+            .into_owned();
+        let size = match field(self.size) {
+            Some(size) => size.parse().map_err(|_| Error::InvalidInventory {
+                reason: format!("invalid size `{}` of {}", size, key),
+            })?,
+            None => 0,
+        };
+        let last_modified = match field(self.last_modified) {
+            Some(date) => Some(
+                DateTime::from_str(date, DateTimeFormat::DateTime)
+                    .context(err::InvalidDate { date })?,
+            ),
+            None => None,
+        };
+        Ok(Some(
+            Object::builder()
+                .key(key)
+                .size(size)
+                // Without the quotes that S3 returns in listings
+                .set_e_tag(field(self.etag).map(|etag| format!("\"{}\"", etag.trim_matches('"'))))
+                .set_last_modified(last_modified)
+                .set_storage_class(field(self.storage_class).map(ObjectStorageClass::from))
+                .build(),
+        ))
+    }
+}
+
+/// The maximum number of objects in a page, the same as ListObjectsV2 uses.
+const PAGE_SIZE: usize = 1000;
+
+fn page(objects: Vec<Object>) -> ListObjectsV2Output {
+    ListObjectsV2Output::builder()
+        .key_count(objects.len() as i32)
+        .set_contents(Some(objects))
+        .build()
+}
+
+/// Parse a (possibly gzipped) CSV data file into pages of objects under `prefix`. The rows are
+/// parsed as the pages are consumed, so only one page of objects is held at a time.
+fn parse_data_file(
+    data: Vec<u8>,
+    columns: Columns,
+    prefix: String,
+) -> impl Iterator<Item = Result<ListObjectsV2Output, Error>> + Send {
+    let reader: Box<dyn Read + Send> = if data.starts_with(&[0x1f, 0x8b]) {
+        Box::new(flate2::read::GzDecoder::new(Cursor::new(data)))
+    } else {
+        Box::new(Cursor::new(data))
+    };
+    let mut rows = csv::ReaderBuilder::new()
+        .has_headers(false)
+        .from_reader(reader)
+        .into_records();
+    let mut done = false;
+    std::iter::from_fn(move || {
+        let mut objects = vec![];
+        while !done && objects.len() < PAGE_SIZE {
+            let obj = match rows.next() {
+                Some(row) => row
+                    .map_err(Error::from)
+                    .and_then(|row| columns.object(&row)),
+                None => {
+                    done = true;
+                    break;
+                }
+            };
+            match obj {
+                Ok(Some(obj)) if obj.key.as_ref().is_some_and(|key| key.starts_with(&prefix)) => {
+                    objects.push(obj)
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    done = true;
+                    return Some(Err(e));
+                }
+            }
+        }
+        Some(objects)
+            .filter(|objects| !objects.is_empty())
+            .map(page)
+            .map(Ok)
+    })
+}
+
+impl S3Algo {
+    /// List the objects of a bucket from an S3 Inventory report instead of with ListObjectsV2.
+    /// `manifest_key` is the key of the report's `manifest.json` in `manifest_bucket`.
+    ///
+    /// The listing contains the current version of every object in the report (older versions and
+    /// delete markers are skipped), optionally only those under `prefix`. Its bucket is the
+    /// source bucket of the report, so `delete_all` and the downloads act on the inventoried
+    /// objects. Only reports in CSV format are supported. ETags are quoted, like in listings.
+    ///
+    /// The data files are downloaded one at a time. Each is held in memory while its rows are
+    /// parsed, one page at a time. Only the manifest is read before this function returns.
+    ///
+    /// The objects are not in key order across data files. With `sort`, all objects (under
+    /// `prefix`) are read into memory first and yielded in key order, as needed by
+    /// `ListObjects::diff`.
+    pub async fn list_inventory(
+        &self,
+        manifest_bucket: String,
+        manifest_key: String,
+        prefix: Option<String>,
+        sort: bool,
+    ) -> Result<
+        ListObjects<impl Stream<Item = Result<ListObjectsV2Output, Error>> + Sized + Send>,
+        Error,
+    > {
+        let s3 = self.s3.clone();
+        let timeout = Arc::new(Mutex::new(TimeoutState::new(
+            self.config.algorithm.clone(),
+            self.config.put_requests.clone(),
+        )));
+        let n_retries = self.config.algorithm.n_retries;
+        let download = move |bucket: String, key: String, size: Option<i64>| {
+            // S3 writes inventory reports without SSE-C, so they are read without
+            download_object(
+                s3.clone(),
+                Encryption::None,
+                bucket,
+                key,
+                size.unwrap_or(0) as usize,
+                n_retries,
+                timeout.clone(),
+            )
+            .map_ok(|(_, data)| data)
+        };
+
+        let data = download(manifest_bucket, manifest_key, None).await?;
+        let manifest = serde_json::from_slice::<InventoryManifest>(&data)?;
+        if !manifest.file_format.eq_ignore_ascii_case("CSV") {
+            return Err(Error::InvalidInventory {
+                reason: format!("unsupported file format `{}`", manifest.file_format),
+            });
+        }
+        let columns = Columns::from_schema(&manifest.file_schema)?;
+        let data_bucket = manifest.data_bucket().to_owned();
+        let prefix = prefix.unwrap_or_default();
+        let prefix2 = prefix.clone();
+
+        let pages = stream::iter(manifest.files)
+            .then(move |file| download(data_bucket.clone(), file.key, file.size))
+            .map_ok(move |data| {
+                stream::iter(parse_data_file(data, columns.clone(), prefix2.clone()))
+            })
+            .try_flatten();
+        let stream = if sort {
+            pages
+                .map_ok(|page| stream::iter(page.contents.unwrap_or_default()).map(Ok))
+                .try_flatten()
+                .try_collect::<Vec<_>>()
+                .map_ok(|mut objects| {
+                    objects.sort_by(|a, b| a.key.cmp(&b.key));
+                    let mut objects = objects.into_iter();
+                    stream::iter(std::iter::from_fn(move || {
+                        let objects = objects.by_ref().take(PAGE_SIZE).collect::<Vec<_>>();
+                        Some(objects)
+                            .filter(|objects| !objects.is_empty())
+                            .map(page)
+                            .map(Ok)
+                    }))
+                })
+                .try_flatten_stream()
+                .boxed()
+        } else {
+            pages.boxed()
+        };
+
+        Ok(ListObjects {
+            s3: self.s3.clone(),
+            config: self.config.clone(),
+            bucket: manifest.source_bucket,
+            prefix,
+            encryption: self.encryption.clone(),
+            stream,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::rand_string;
+    use std::io::Write;
+
+    #[test]
+    fn parse_rows() {
+        let columns = Columns::from_schema(
+            "Bucket, Key, VersionId, IsLatest, IsDeleteMarker, Size, LastModifiedDate, ETag",
+        )
+        .unwrap();
+        let csv = "\"b\",\"dir/a+b%2Bc\",\"v2\",\"true\",\"false\",\"3\",\"2023-10-01T12:00:00.000Z\",\"e1\"\n\
+                   \"b\",\"dir/a+b%2Bc\",\"v1\",\"false\",\"false\",\"2\",\"2023-09-01T12:00:00.000Z\",\"e0\"\n\
+                   \"b\",\"dir/d\",\"v1\",\"true\",\"true\",\"\",\"2023-09-01T12:00:00.000Z\",\"\"\n\
+                   \"b\",\"other/e\",\"v1\",\"true\",\"false\",\"1\",\"2023-09-01T12:00:00.000Z\",\"e2\"\n";
+        let pages = parse_data_file(csv.as_bytes().to_vec(), columns, "dir/".into())
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(pages.len(), 1);
+        let objects = pages[0].contents.clone().unwrap();
+        assert_eq!(objects.len(), 1);
+        assert_eq!(objects[0].key.as_deref(), Some("dir/a b+c"));
+        assert_eq!(objects[0].size, 3);
+        assert_eq!(objects[0].e_tag.as_deref(), Some("\"e1\""));
+        assert_eq!(
+            objects[0].last_modified,
+            Some(DateTime::from_secs(1_696_161_600))
+        );
+
+        assert!(Columns::from_schema("Bucket, Size").is_err());
+
+        let columns = Columns::from_schema("Bucket, Key").unwrap();
+        let csv = (0..2500)
+            .map(|i| format!("\"b\",\"{}\"\n", i))
+            .collect::<String>();
+        let page_sizes = parse_data_file(csv.into_bytes(), columns, String::new())
+            .map(|page| page.unwrap().contents.unwrap().len())
+            .collect::<Vec<_>>();
+        assert_eq!(page_sizes, vec![1000, 1000, 500]);
+    }
+
+    #[tokio::test]
+    async fn test_s3_list_inventory() {
+        let algo = S3Algo::new(testing_sdk_client().await);
+        let dir = rand_string(14);
+        let row = |key: &str, size| format!("\"source\",\"{}/{}\",\"{}\"\n", dir, key, size);
+
+        // One plain and one gzipped data file, not in key order across files
+        let plain = row("a", 1) + &row("c", 3);
+        let mut gz = flate2::write::GzEncoder::new(vec![], flate2::Compression::default());
+        gz.write_all(row("b", 2).as_bytes()).unwrap();
+        let gz = gz.finish().unwrap();
+        let manifest = format!(
+            r#"{{
+                "sourceBucket": "source",
+                "destinationBucket": "arn:aws:s3:::test-bucket",
+                "version": "2016-11-30",
+                "fileFormat": "CSV",
+                "fileSchema": "Bucket, Key, Size",
+                "files": [
+                    {{"key": "{dir}/data/1.csv", "size": {}}},
+                    {{"key": "{dir}/data/2.csv.gz", "size": {}}}
+                ]
+            }}"#,
+            plain.len(),
+            gz.len(),
+            dir = dir
+        );
+        let files = vec![
+            ObjectSource::data(manifest, format!("{}/manifest.json", dir)),
+            ObjectSource::data(plain, format!("{}/data/1.csv", dir)),
+            ObjectSource::data(gz, format!("{}/data/2.csv.gz", dir)),
+        ];
+        algo.upload_files(
+            "test-bucket".into(),
+            files.into_iter(),
+            |_| async {},
+            |client| client.put_object(),
+        )
+        .await
+        .unwrap();
+
+        let list = |sort| {
+            algo.list_inventory(
+                "test-bucket".into(),
+                format!("{}/manifest.json", dir),
+                None,
+                sort,
+            )
+            .map_ok(|listing| {
+                listing
+                    .flatten()
+                    .map_ok(|obj| (obj.key.unwrap(), obj.size))
+                    .try_collect::<Vec<_>>()
+            })
+            .and_then(|objects| objects)
+        };
+        let expected = |names: &[&str]| {
+            names
+                .iter()
+                .map(|name| {
+                    (
+                        format!("{}/{}", dir, name),
+                        (name.as_bytes()[0] - b'a') as i64 + 1,
+                    )
+                })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(list(false).await.unwrap(), expected(&["a", "c", "b"]));
+        assert_eq!(list(true).await.unwrap(), expected(&["a", "b", "c"]));
+    }
+}
@@ -0,0 +1,285 @@
+//! Mapping between relative file paths and S3 keys, independent of the platform's path separator.
+use crate::err::{self, Error};
+use percent_encoding::percent_decode_str;
+use std::ffi::{OsStr, OsString};
+use std::fmt;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+use unicode_normalization::UnicodeNormalization;
+
+/// What to do with file names that are not valid UTF-8, and can thus not be used in a key as
+/// they are.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InvalidNames {
+    /// Replace invalid sequences with `U+FFFD`. The file name can not be recovered from the key.
+    Lossy,
+    /// Percent-encode the invalid bytes, and also every `%`, so that the file name can be
+    /// recovered from the key. Only supported on unix; elsewhere such names are rejected.
+    PercentEncode,
+    /// Fail with `Error::InvalidName`.
+    Reject,
+}
+
+/// Unicode normalization form to apply to keys.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Normalization {
+    Nfc,
+    Nfd,
+    Nfkc,
+    Nfkd,
+}
+
+type Rename = Arc<dyn Fn(&str) -> String + Send + Sync>;
+
+/// Maps a path relative to an uploaded directory to a key (without key prefix), and back.
+///
+/// Path components are always joined with `/`. Normalization is applied after the mapping to a
+/// key, and then the user-supplied `rename`. The inverse, `path`, undoes `rename` and the encoding
+/// of invalid names, but not the normalization.
+///
+/// The default mapping replaces invalid UTF-8 lossily, like `files_recursive` always did.
+#[derive(Clone)]
+pub struct KeyMapping {
+    invalid_names: InvalidNames,
+    normalization: Option<Normalization>,
+    rename: Option<Rename>,
+    rename_inverse: Option<Rename>,
+}
+
+impl Default for KeyMapping {
+    fn default() -> Self {
+        Self {
+            invalid_names: InvalidNames::Lossy,
+            normalization: None,
+            rename: None,
+            rename_inverse: None,
+        }
+    }
+}
+
+impl fmt::Debug for KeyMapping {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("KeyMapping")
+            .field("invalid_names", &self.invalid_names)
+            .field("normalization", &self.normalization)
+            .field("rename", &self.rename.is_some())
+            .field("rename_inverse", &self.rename_inverse.is_some())
+            .finish()
+    }
+}
+
+impl KeyMapping {
+    pub fn invalid_names(mut self, invalid_names: InvalidNames) -> Self {
+        self.invalid_names = invalid_names;
+        self
+    }
+    pub fn normalization(mut self, normalization: Option<Normalization>) -> Self {
+        self.normalization = normalization;
+        self
+    }
+    /// Rename every key as the last step of the mapping.
+    pub fn rename<F>(mut self, rename: F) -> Self
+    where
+        F: Fn(&str) -> String + Send + Sync + 'static,
+    {
+        self.rename = Some(Arc::new(rename));
+        self
+    }
+    /// The inverse of `rename`, needed by `path` if `rename` is set.
+    pub fn rename_inverse<F>(mut self, inverse: F) -> Self
+    where
+        F: Fn(&str) -> String + Send + Sync + 'static,
+    {
+        self.rename_inverse = Some(Arc::new(inverse));
+        self
+    }
+
+    /// Map a relative path to a key.
+    pub fn key(&self, path: &Path) -> Result<String, Error> {
+        let mut components = vec![];
+        for component in path.components() {
+            match component {
+                Component::Normal(name) => components.push(self.encode(name)?),
+                Component::CurDir => {}
+                _ => {
+                    return err::InvalidName {
+                        name: path.display().to_string(),
+                        reason: "only relative paths without `..` can be mapped",
+                    }
+                    .fail()
+                }
+            }
+        }
+        let key = components.join("/");
+        let key = match self.normalization {
+            None => key,
+            Some(Normalization::Nfc) => key.nfc().collect(),
+            Some(Normalization::Nfd) => key.nfd().collect(),
+            Some(Normalization::Nfkc) => key.nfkc().collect(),
+            Some(Normalization::Nfkd) => key.nfkd().collect(),
+        };
+        Ok(match &self.rename {
+            Some(rename) => rename(&key),
+            None => key,
+        })
+    }
+
+    /// Map a key (without key prefix) back to a relative path, as `ListObjects::download_all_to_dir`
+    /// does. Fails for keys that do not map to a single relative path, such as
+    /// keys with empty, `.` or `..` components.
+    pub fn path(&self, key: &str) -> Result<PathBuf, Error> {
+        let key = match (&self.rename, &self.rename_inverse) {
+            (_, Some(inverse)) => inverse(key),
+            (Some(_), None) => {
+                return err::InvalidName {
+                    name: key,
+                    reason: "`rename` is set without `rename_inverse`",
+                }
+                .fail()
+            }
+            (None, None) => key.to_owned(),
+        };
+        let mut path = PathBuf::new();
+        for component in key.split('/') {
+            let name = self.decode(component)?;
+            let mut components = Path::new(&name).components();
+            match (components.next(), components.next()) {
+                (Some(Component::Normal(_)), None) => path.push(name),
+                _ => {
+                    return err::InvalidName {
+                        name: key.clone(),
+                        reason: format!("`{}` is not a file name", component),
+                    }
+                    .fail()
+                }
+            }
+        }
+        Ok(path)
+    }
+
+    fn encode(&self, name: &OsStr) -> Result<String, Error> {
+        match (name.to_str(), self.invalid_names) {
+            (Some(name), InvalidNames::PercentEncode) => Ok(name.replace('%', "%25")),
+            (Some(name), _) => Ok(name.to_owned()),
+            (None, InvalidNames::Lossy) => Ok(name.to_string_lossy().into_owned()),
+            (None, InvalidNames::PercentEncode) if os_str_bytes(name).is_some() => {
+                Ok(percent_encode_invalid(os_str_bytes(name).unwrap()))
+            }
+            (None, _) => err::InvalidName {
+                name: name.to_string_lossy(),
+                reason: "not valid UTF-8",
+            }
+            .fail(),
+        }
+    }
+
+    fn decode(&self, component: &str) -> Result<OsString, Error> {
+        match self.invalid_names {
+            InvalidNames::PercentEncode => {
+                os_string_from_bytes(percent_decode_str(component).collect()).map_err(|bytes| {
+                    Error::InvalidName {
+                        name: String::from_utf8_lossy(&bytes).into_owned(),
+                        reason: "not valid UTF-8".to_owned(),
+                    }
+                })
+            }
+            _ => Ok(OsString::from(component)),
+        }
+    }
+}
+
+/// Percent-encode the bytes that are not valid UTF-8, as well as `%`.
+fn percent_encode_invalid(mut bytes: &[u8]) -> String {
+    let mut out = String::new();
+    loop {
+        match std::str::from_utf8(bytes) {
+            Ok(valid) => {
+                out.push_str(&valid.replace('%', "%25"));
+                return out;
+            }
+            Err(e) => {
+                let (valid, rest) = bytes.split_at(e.valid_up_to());
+                out.push_str(&std::str::from_utf8(valid).unwrap().replace('%', "%25"));
+                let invalid_len = e.error_len().unwrap_or(rest.len());
+                for byte in &rest[..invalid_len] {
+                    out.push_str(&format!("%{:02X}", byte));
+                }
+                bytes = &rest[invalid_len..];
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+fn os_str_bytes(name: &OsStr) -> Option<&[u8]> {
+    use std::os::unix::ffi::OsStrExt;
+    Some(name.as_bytes())
+}
+#[cfg(not(unix))]
+fn os_str_bytes(_: &OsStr) -> Option<&[u8]> {
+    None
+}
+
+#[cfg(unix)]
+fn os_string_from_bytes(bytes: Vec<u8>) -> Result<OsString, Vec<u8>> {
+    use std::os::unix::ffi::OsStringExt;
+    Ok(OsString::from_vec(bytes))
+}
+#[cfg(not(unix))]
+fn os_string_from_bytes(bytes: Vec<u8>) -> Result<OsString, Vec<u8>> {
+    String::from_utf8(bytes)
+        .map(OsString::from)
+        .map_err(|e| e.into_bytes())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn portable_separators() {
+        let mapping = KeyMapping::default();
+        let path = Path::new("a").join("b").join("c.txt");
+        assert_eq!(mapping.key(&path).unwrap(), "a/b/c.txt");
+        assert_eq!(mapping.path("a/b/c.txt").unwrap(), path);
+        assert!(mapping.key(Path::new("../a")).is_err());
+        assert!(mapping.path("a/../b").is_err());
+        assert!(mapping.path("a//b").is_err());
+    }
+
+    #[test]
+    fn percent_encoding_roundtrip() {
+        let mapping = KeyMapping::default().invalid_names(InvalidNames::PercentEncode);
+        let path = Path::new("100%").join("ø.txt");
+        assert_eq!(mapping.key(&path).unwrap(), "100%25/ø.txt");
+        assert_eq!(mapping.path("100%25/ø.txt").unwrap(), path);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn invalid_utf8() {
+        use std::os::unix::ffi::OsStrExt;
+        let path = Path::new(OsStr::from_bytes(b"a\xffb%"));
+
+        let mapping = KeyMapping::default().invalid_names(InvalidNames::PercentEncode);
+        let key = mapping.key(path).unwrap();
+        assert_eq!(key, "a%FFb%25");
+        assert_eq!(mapping.path(&key).unwrap(), path);
+
+        assert_eq!(KeyMapping::default().key(path).unwrap(), "a\u{fffd}b%");
+        let reject = KeyMapping::default().invalid_names(InvalidNames::Reject);
+        assert!(reject.key(path).is_err());
+    }
+
+    #[test]
+    fn normalization_and_rename() {
+        let mapping = KeyMapping::default()
+            .normalization(Some(Normalization::Nfc))
+            .rename(|key| key.to_uppercase());
+        assert_eq!(mapping.key(Path::new("e\u{301}")).unwrap(), "\u{c9}");
+        assert!(mapping.path("\u{c9}").is_err());
+
+        let mapping = mapping.rename_inverse(|key| key.to_lowercase());
+        assert_eq!(mapping.path("\u{c9}").unwrap(), Path::new("\u{e9}"));
+    }
+}
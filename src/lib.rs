@@ -3,9 +3,16 @@
 //!
 //! https://docs.aws.amazon.com/AmazonS3/latest/dev/optimizing-performance-guidelines.html
 //!
-//! - Upload multiple files with `S3Algo::upload_files`.
+//! - Upload multiple files with `S3Algo::upload_files`, or pack many small files into tar shards
+//!   with `S3Algo::upload_files_packed`.
 //! - List files with `S3Algo::s3_list_objects` or `S3Algo::s3_list_prefix`,
-//! and then execute deletion or copy on all the files.
+//!   and then execute deletion or copy on all the files. Browse prefixes as directories with
+//!   `S3Algo::list_dir` and `S3Algo::walk_dirs`.
+
+// `Error` holds the (large) errors of the AWS SDK, and is returned everywhere.
+#![allow(clippy::result_large_err)]
+// The tests keep casts of sizes that are `usize` by now.
+#![cfg_attr(test, allow(clippy::unnecessary_cast))]
 
 use crate::timeout::*;
 use aws_config::default_provider::credentials::DefaultCredentialsChain;
@@ -26,14 +33,50 @@ use std::time::Duration;
 use tokio::sync::Mutex;
 
 mod config;
+mod copy;
+mod diff;
+mod encryption;
 pub mod err;
+mod glacier;
+#[cfg(feature = "serde1")]
+mod inventory;
+mod key_mapping;
 mod list_actions;
+mod list_dir;
+mod list_versions;
+#[cfg(feature = "serde1")]
+mod manifest;
+mod metadata;
+#[cfg(feature = "serde1")]
+mod pack;
+mod presign;
+mod protection;
+mod rewrite;
+mod storage_class;
+mod summary;
+mod tagging;
+mod transform;
 mod upload;
 
+pub use key_mapping::*;
 pub use list_actions::*;
+pub use list_dir::*;
+pub use list_versions::*;
+#[cfg(feature = "serde1")]
+pub use manifest::*;
+pub use metadata::*;
+#[cfg(feature = "serde1")]
+pub use pack::*;
+pub use presign::*;
+pub use protection::*;
+pub use rewrite::*;
+pub use summary::*;
+pub use tagging::*;
 pub use upload::*;
 pub mod timeout;
 pub use config::*;
+pub use diff::*;
+pub use encryption::*;
 pub use err::Error;
 
 #[cfg(test)]
@@ -43,16 +86,27 @@ mod test;
 pub struct S3Algo {
     s3: Client,
     config: Config,
+    encryption: Encryption,
 }
 impl S3Algo {
     pub fn new(s3: Client) -> Self {
         Self {
             s3,
             config: Config::default(),
+            encryption: Encryption::default(),
         }
     }
     pub fn with_config(s3: Client, config: Config) -> Self {
-        Self { s3, config }
+        Self {
+            s3,
+            config,
+            encryption: Encryption::default(),
+        }
+    }
+    /// Use `encryption` for all requests that read or write object data.
+    pub fn with_encryption(mut self, encryption: Encryption) -> Self {
+        self.encryption = encryption;
+        self
     }
 }
 
@@ -1,28 +1,34 @@
 use super::*;
 use aws_sdk_s3::operation::list_objects_v2::ListObjectsV2Output;
-use aws_sdk_s3::primitives::ByteStream;
-use aws_sdk_s3::types::{Delete, Object, ObjectIdentifier};
+use aws_sdk_s3::primitives::{ByteStream, DateTime};
+use aws_sdk_s3::types::{Delete, Object, ObjectIdentifier, ObjectStorageClass};
 use futures::future::ok;
-use futures::stream::Stream;
+use futures::stream::{BoxStream, FusedStream, Stream};
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
 use std::future::Future;
+use std::ops::RangeBounds;
+use std::path::Path;
 use std::pin::Pin;
 use std::task::{Context, Poll};
 use tokio::io;
+use tokio::io::AsyncWriteExt;
 
 /// A stream that can list objects, and (using member functions) delete or copy listed files.
 pub struct ListObjects<S> {
-    s3: Client,
-    config: Config,
-    bucket: String,
+    pub(crate) s3: Client,
+    pub(crate) config: Config,
+    pub(crate) bucket: String,
     /// Common prefix (as requested) of the listed objects. Empty string if all objects were
-    /// requestd.
-    prefix: String,
-    stream: S,
+    /// requested.
+    pub(crate) prefix: String,
+    pub(crate) encryption: Encryption,
+    pub(crate) stream: S,
 }
 impl<S> ListObjects<S>
 where
     S: Stream<Item = Result<ListObjectsV2Output, Error>> + Sized + Send + 'static,
 {
+    #[allow(clippy::type_complexity)]
     pub fn boxed(
         self,
     ) -> ListObjects<Pin<Box<dyn Stream<Item = Result<ListObjectsV2Output, Error>> + Send>>> {
@@ -32,9 +38,136 @@ where
             bucket: self.bucket,
             stream: self.stream.boxed(),
             prefix: self.prefix,
+            encryption: self.encryption,
+        }
+    }
+
+    /// The bucket of the listed objects.
+    pub fn bucket(&self) -> &str {
+        &self.bucket
+    }
+
+    /// Common prefix (as requested) of the listed objects. Empty string if all objects were
+    /// requested.
+    pub fn prefix(&self) -> &str {
+        &self.prefix
+    }
+
+    /// Override the encryption settings inherited from `S3Algo` for the actions on this listing.
+    pub fn with_encryption(mut self, encryption: Encryption) -> Self {
+        self.encryption = encryption;
+        self
+    }
+
+    /// Keep only the listed objects for which `predicate` returns `true`. All other actions, such
+    /// as `delete_all` and the downloads, then only see these objects.
+    ///
+    /// Filters can be chained. For example, to delete everything under `logs/` that is older
+    /// than 30 days:
+    /// ```no_run
+    /// # use s3_algo::*;
+    /// # use std::time::{Duration, SystemTime};
+    /// # async fn f(algo: S3Algo) -> Result<(), Error> {
+    /// let month_ago = SystemTime::now() - Duration::from_secs(30 * 24 * 3600);
+    /// algo.list_prefix("bucket".into(), Some("logs/".into()))
+    ///     .modified_before(month_ago)
+    ///     .delete_all(|_| async {}, |_| async {})
+    ///     .await
+    /// # }
+    /// ```
+    pub fn filter<P>(
+        self,
+        predicate: P,
+    ) -> ListObjects<impl Stream<Item = Result<ListObjectsV2Output, Error>> + Sized + Send>
+    where
+        P: Fn(&Object) -> bool + Send + Sync + 'static,
+    {
+        let ListObjects {
+            s3,
+            config,
+            bucket,
+            prefix,
+            encryption,
+            stream,
+        } = self;
+        ListObjects {
+            s3,
+            config,
+            bucket,
+            prefix,
+            encryption,
+            stream: stream.map_ok(move |mut response| {
+                response.contents = response
+                    .contents
+                    .map(|objects| objects.into_iter().filter(|obj| predicate(obj)).collect());
+                response
+            }),
         }
     }
 
+    /// Keep only objects whose key matches `glob`. Note that `*` also matches `/`.
+    pub fn filter_key_glob(
+        self,
+        glob: &str,
+    ) -> Result<
+        ListObjects<impl Stream<Item = Result<ListObjectsV2Output, Error>> + Sized + Send>,
+        Error,
+    > {
+        let matcher = globset::Glob::new(glob)
+            .context(err::Glob { glob })?
+            .compile_matcher();
+        Ok(self.filter(move |obj| obj.key.as_ref().is_some_and(|key| matcher.is_match(key))))
+    }
+
+    /// Keep only objects whose key matches the regular expression `regex`.
+    pub fn filter_key_regex(
+        self,
+        regex: &str,
+    ) -> Result<
+        ListObjects<impl Stream<Item = Result<ListObjectsV2Output, Error>> + Sized + Send>,
+        Error,
+    > {
+        let regex = regex::Regex::new(regex).context(err::Regex { regex })?;
+        Ok(self.filter(move |obj| obj.key.as_ref().is_some_and(|key| regex.is_match(key))))
+    }
+
+    /// Keep only objects with a size in bytes within `range`.
+    pub fn filter_size<R>(
+        self,
+        range: R,
+    ) -> ListObjects<impl Stream<Item = Result<ListObjectsV2Output, Error>> + Sized + Send>
+    where
+        R: RangeBounds<i64> + Send + Sync + 'static,
+    {
+        self.filter(move |obj| range.contains(&obj.size))
+    }
+
+    /// Keep only objects that were last modified before `time`.
+    pub fn modified_before<T: Into<DateTime>>(
+        self,
+        time: T,
+    ) -> ListObjects<impl Stream<Item = Result<ListObjectsV2Output, Error>> + Sized + Send> {
+        let time = time.into();
+        self.filter(move |obj| obj.last_modified.is_some_and(|modified| modified < time))
+    }
+
+    /// Keep only objects that were last modified after `time`.
+    pub fn modified_after<T: Into<DateTime>>(
+        self,
+        time: T,
+    ) -> ListObjects<impl Stream<Item = Result<ListObjectsV2Output, Error>> + Sized + Send> {
+        let time = time.into();
+        self.filter(move |obj| obj.last_modified.is_some_and(|modified| modified > time))
+    }
+
+    /// Keep only objects of the given storage class.
+    pub fn filter_storage_class(
+        self,
+        storage_class: ObjectStorageClass,
+    ) -> ListObjects<impl Stream<Item = Result<ListObjectsV2Output, Error>> + Sized + Send> {
+        self.filter(move |obj| obj.storage_class.as_ref() == Some(&storage_class))
+    }
+
     /// Calls an async closure on all the individual objects of the list operation
     pub async fn process<P, F>(self, f: P) -> Result<(), Error>
     where
@@ -68,6 +201,7 @@ where
             bucket,
             stream,
             prefix: _,
+            encryption,
         } = self;
         stream
             .try_filter_map(|response| ok(response.contents))
@@ -84,13 +218,11 @@ where
                 })
             })
             .and_then(move |(key, _)| {
-                let (s3, bucket) = (s3.clone(), bucket.clone());
+                let (s3, bucket, encryption) = (s3.clone(), bucket.clone(), encryption.clone());
 
                 async move {
-                    let output = s3
-                        .get_object()
-                        .bucket(bucket.clone())
-                        .key(key.clone())
+                    let output = encryption
+                        .get_object(s3.get_object().bucket(bucket.clone()).key(key.clone()))
                         .send()
                         .await
                         .context(err::GetObject {
@@ -113,14 +245,156 @@ where
             })
     }
 
-    /*
-    /// Download all listed objects to file system.
-    /// UNIMPLEMENTED.
-    pub fn download_all(self) -> impl Future<Output = Result<(), Error>> {
-        // TODO use download_all_stream
-        ok(unimplemented!())
+    /// Download all listed objects and write them as a single tar archive to `writer`, in the
+    /// order they are listed. The entry of an object is named `mapping(key)`, which must be a
+    /// relative path without `..` segments; otherwise `Error::TarPath` is returned for that key
+    /// before it is downloaded, and the archive is incomplete.
+    ///
+    /// Up to `copy_parallelization` objects are downloaded ahead of the one being written, which
+    /// is thus also the maximum number of objects held in memory at once.
+    /// Returns `writer` when the archive is complete.
+    pub async fn download_all_to_tar<W, F>(self, writer: W, mapping: F) -> Result<W, Error>
+    where
+        W: io::AsyncWrite + Unpin + Send,
+        F: Fn(&str) -> String,
+    {
+        let ListObjects {
+            s3,
+            config,
+            bucket,
+            stream,
+            prefix: _,
+            encryption,
+        } = self;
+        let timeout = Arc::new(Mutex::new(TimeoutState::new(
+            config.algorithm.clone(),
+            config.put_requests.clone(),
+        )));
+        let n_retries = config.algorithm.n_retries;
+        let tar = stream
+            .try_filter_map(|response| ok(response.contents))
+            .map_ok(|x| stream::iter(x).map(Ok))
+            .try_flatten()
+            .map(|result: Result<Object, Error>| {
+                let object = result?;
+                let key = object.key.clone().ok_or(Error::MissingKeyOrSize)?;
+                let path = mapping(&key);
+                if !is_tar_path(&path) {
+                    return Err(Error::TarPath { key, path });
+                }
+                Ok((key, path, object))
+            })
+            .map_ok(move |(key, path, object)| {
+                download_object(
+                    s3.clone(),
+                    encryption.clone(),
+                    bucket.clone(),
+                    key.clone(),
+                    object.size as usize,
+                    n_retries,
+                    timeout.clone(),
+                )
+                .map_ok(move |(_, data)| (path, object, data))
+            })
+            .try_buffered(config.copy_parallelization)
+            .try_fold(
+                tokio_tar::Builder::new_non_terminated(writer),
+                |mut tar, (path, object, data)| async move {
+                    let mut header = tokio_tar::Header::new_gnu();
+                    header.set_size(data.len() as u64);
+                    header.set_mode(0o644);
+                    if let Some(last_modified) = object.last_modified {
+                        header.set_mtime(last_modified.secs().max(0) as u64);
+                    }
+                    tar.append_data(&mut header, &path, &data[..])
+                        .await
+                        .with_context(|| err::Io {
+                            description: format!("writing {} to tar archive", path),
+                        })?;
+                    Ok::<_, Error>(tar)
+                },
+            )
+            .await?;
+        let mut writer = tar.into_inner().await.context(err::TokioIo)?;
+        writer.flush().await.context(err::TokioIo)?;
+        Ok(writer)
+    }
+
+    /// Download all listed objects to files in `dest_dir`. The listed prefix, and a `/` following
+    /// it, are stripped from each key, and the rest is mapped to a relative path with
+    /// `mapping.path`, the inverse of the mapping that `FilesRecursive` uploads files with.
+    /// Missing directories are created, and existing files are overwritten.
+    ///
+    /// Keys ending in `/`, such as the folder markers of the S3 console, are skipped. A key that
+    /// does not map to a relative path fails with `Error::InvalidName` before it is downloaded.
+    /// Up to `copy_parallelization` objects are downloaded at once, and held in memory until they
+    /// are written.
+    pub async fn download_all_to_dir(
+        self,
+        dest_dir: PathBuf,
+        mapping: KeyMapping,
+    ) -> Result<(), Error> {
+        let ListObjects {
+            s3,
+            config,
+            bucket,
+            stream,
+            prefix,
+            encryption,
+        } = self;
+        let timeout = Arc::new(Mutex::new(TimeoutState::new(
+            config.algorithm.clone(),
+            config.put_requests.clone(),
+        )));
+        let n_retries = config.algorithm.n_retries;
+        stream
+            .try_filter_map(|response| ok(response.contents))
+            .map_ok(|x| stream::iter(x).map(Ok))
+            .try_flatten()
+            .map(move |result: Result<Object, Error>| {
+                let object = result?;
+                let key = object.key.ok_or(Error::MissingKeyOrSize)?;
+                let path = dir_path(&dest_dir, &prefix, &key, &mapping)?;
+                let size = object.size;
+                Ok(path.map(|path| (key, path, size)))
+            })
+            .try_filter_map(ok)
+            .map_ok(move |(key, path, size)| {
+                let (s3, encryption, bucket, timeout) = (
+                    s3.clone(),
+                    encryption.clone(),
+                    bucket.clone(),
+                    timeout.clone(),
+                );
+                async move {
+                    let (_, data) = download_object(
+                        s3,
+                        encryption,
+                        bucket,
+                        key,
+                        size as usize,
+                        n_retries,
+                        timeout,
+                    )
+                    .await?;
+                    if let Some(parent) = path.parent() {
+                        tokio::fs::create_dir_all(parent)
+                            .await
+                            .with_context(|| err::Io {
+                                description: parent.display().to_string(),
+                            })?;
+                    }
+                    tokio::fs::write(&path, data)
+                        .await
+                        .with_context(|| err::Io {
+                            description: path.display().to_string(),
+                        })
+                }
+            })
+            .try_buffer_unordered(config.copy_parallelization)
+            .try_collect()
+            .await
     }
-    */
 
     /// Delete all listed objects.
     ///
@@ -150,6 +424,7 @@ where
             bucket,
             stream,
             prefix: _,
+            encryption: _,
         } = self;
         let timeout = Arc::new(Mutex::new(TimeoutState::new(
             config.algorithm.clone(),
@@ -157,16 +432,9 @@ where
         )));
         let n_retries = config.algorithm.n_retries;
         stream.try_for_each_concurrent(None, move |object| {
-            let (s3, bucket, timeout, delete_progress2, list_progress2) = (
-                s3.clone(),
-                bucket.clone(),
-                timeout.clone(),
-                delete_progress.clone(),
-                list_progress.clone(),
-            );
             let objects = object
                 .contents
-                .unwrap()
+                .unwrap_or_default()
                 .iter()
                 .filter_map(|obj| {
                     obj.key.as_ref().map(|key| {
@@ -177,40 +445,15 @@ where
                     })
                 })
                 .collect::<Vec<_>>();
-            let n_objects = objects.len();
-
-            async move {
-                list_progress2(n_objects).await;
-                let (report, _) = s3_request(
-                    move || {
-                        let (s3, bucket, objects) = (s3.clone(), bucket.clone(), objects.clone());
-                        async move {
-                            let (s3, bucket, objects) =
-                                (s3.clone(), bucket.clone(), objects.clone());
-                            Ok((
-                                async move {
-                                    s3.delete_objects()
-                                        .set_bucket(Some(bucket))
-                                        .set_delete(Some(
-                                            Delete::builder().set_objects(Some(objects)).build(),
-                                        ))
-                                        .send()
-                                        .await
-                                        .map_err(|e| e.into())
-                                },
-                                n_objects,
-                            ))
-                        }
-                    },
-                    |_, size| size,
-                    n_retries,
-                    timeout.clone(),
-                )
-                .await?;
-                timeout.lock().await.update(&report);
-                delete_progress2(report).await;
-                Ok(())
-            }
+            delete_identifiers(
+                s3.clone(),
+                bucket.clone(),
+                objects,
+                n_retries,
+                timeout.clone(),
+                list_progress.clone(),
+                delete_progress.clone(),
+            )
         })
     }
 
@@ -382,20 +625,173 @@ where
     }
 }
 
+/// Characters to percent-encode in the key of a copy source: all but the unreserved characters of
+/// RFC 3986, and `/`.
+const COPY_SOURCE_KEY: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~')
+    .remove(b'/');
+
+/// The `x-amz-copy-source` of a CopyObject request, optionally of a specific version.
+pub(crate) fn copy_source(bucket: &str, key: &str, version_id: Option<&str>) -> String {
+    let source = format!("{}/{}", bucket, utf8_percent_encode(key, COPY_SOURCE_KEY));
+    match version_id {
+        Some(version_id) => format!("{}?versionId={}", source, version_id),
+        None => source,
+    }
+}
+
+/// Download an object into memory, with the timeouts and retries of `s3_request`.
+/// `size` is the expected size in bytes, used for the timeout.
+pub(crate) async fn download_object(
+    s3: Client,
+    encryption: Encryption,
+    bucket: String,
+    key: String,
+    size: usize,
+    n_retries: usize,
+    timeout: Arc<Mutex<TimeoutState>>,
+) -> Result<(RequestReport, Vec<u8>), Error> {
+    let (report, data) = s3_request(
+        move || {
+            let (s3, bucket, key, encryption) =
+                (s3.clone(), bucket.clone(), key.clone(), encryption.clone());
+            async move {
+                Ok((
+                    async move {
+                        let output = encryption
+                            .get_object(s3.get_object().bucket(bucket.clone()).key(key.clone()))
+                            .send()
+                            .await
+                            .context(err::GetObject { key, bucket })?;
+                        Ok::<_, Error>(output.body.collect().await?.into_bytes().to_vec())
+                    },
+                    size,
+                ))
+            }
+        },
+        |data: &Vec<u8>, _| data.len(),
+        n_retries,
+        timeout.clone(),
+    )
+    .await?;
+    timeout.lock().await.update(&report);
+    Ok((report, data))
+}
+
+/// The path that `ListObjects::download_all_to_dir` writes the object `key` to: `prefix` and a
+/// following `/` are stripped, and the rest is mapped with `mapping.path`. `None` for a key ending
+/// in `/`, which is a folder marker rather than a file.
+fn dir_path(
+    dest_dir: &Path,
+    prefix: &str,
+    key: &str,
+    mapping: &KeyMapping,
+) -> Result<Option<PathBuf>, Error> {
+    if key.ends_with('/') {
+        return Ok(None);
+    }
+    let relative = key.strip_prefix(prefix).unwrap_or(key);
+    let relative = relative.strip_prefix('/').unwrap_or(relative);
+    Ok(Some(dest_dir.join(mapping.path(relative)?)))
+}
+
+/// Whether `path` can name a tar entry: tar archives only hold relative paths without `..`.
+fn is_tar_path(path: &str) -> bool {
+    !path.is_empty() && !path.starts_with('/') && path.split('/').all(|segment| segment != "..")
+}
+
+/// Delete up to 1000 objects (or versions) with one DeleteObjects request, as listed in one page.
+/// `list_progress` is called with the number of objects, and `delete_progress` with the
+/// `RequestReport` of the request, like in `ListObjects::delete_all`.
+pub(crate) async fn delete_identifiers<P1, P2, F1, F2>(
+    s3: Client,
+    bucket: String,
+    objects: Vec<ObjectIdentifier>,
+    n_retries: usize,
+    timeout: Arc<Mutex<TimeoutState>>,
+    list_progress: P1,
+    delete_progress: P2,
+) -> Result<(), Error>
+where
+    P1: Fn(usize) -> F1,
+    P2: Fn(RequestReport) -> F2,
+    F1: Future<Output = ()>,
+    F2: Future<Output = ()>,
+{
+    let n_objects = objects.len();
+    list_progress(n_objects).await;
+    if n_objects == 0 {
+        // Empty listing, or all entries filtered out
+        return Ok(());
+    }
+    let (report, _) = s3_request(
+        move || {
+            let (s3, bucket, objects) = (s3.clone(), bucket.clone(), objects.clone());
+            async move {
+                Ok((
+                    async move {
+                        s3.delete_objects()
+                            .bucket(bucket)
+                            .delete(Delete::builder().set_objects(Some(objects)).build())
+                            .send()
+                            .await
+                            .map_err(|e| e.into())
+                    },
+                    n_objects,
+                ))
+            }
+        },
+        |_, size| size,
+        n_retries,
+        timeout.clone(),
+    )
+    .await?;
+    timeout.lock().await.update(&report);
+    delete_progress(report).await;
+    Ok(())
+}
+
 impl S3Algo {
     /// List objects of a bucket.
     pub fn list_prefix(
         &self,
         bucket: String,
         prefix: Option<String>,
+    ) -> ListObjects<impl Stream<Item = Result<ListObjectsV2Output, Error>> + Sized + Send> {
+        self.list_prefix_with(bucket, prefix, ListOptions::default())
+    }
+
+    /// List objects of a bucket, with more control over the listing (see `ListOptions`).
+    ///
+    /// To checkpoint a long listing job, save the `next_continuation_token` of the last processed
+    /// page (the `ListObjects` is a stream of pages), and resume later by passing it as
+    /// `ListOptions::continuation_token`.
+    pub fn list_prefix_with(
+        &self,
+        bucket: String,
+        prefix: Option<String>,
+        options: ListOptions,
     ) -> ListObjects<impl Stream<Item = Result<ListObjectsV2Output, Error>> + Sized + Send> {
         // TODO: Reintroduce retry and timeout
 
+        let ListOptions {
+            start_after,
+            max_keys,
+            fetch_owner,
+            continuation_token,
+        } = options;
         let stream = self
             .s3
             .list_objects_v2()
             .bucket(bucket.clone())
-            .set_prefix(prefix)
+            .set_prefix(prefix.clone())
+            .set_start_after(start_after)
+            .set_max_keys(max_keys)
+            .fetch_owner(fetch_owner)
+            .set_continuation_token(continuation_token)
             .into_paginator()
             .send()
             // Turn into a stream of Objects
@@ -406,9 +802,200 @@ impl S3Algo {
             config: self.config.clone(),
             stream,
             bucket,
-            prefix: String::new(),
+            prefix: prefix.unwrap_or_default(),
+            encryption: self.encryption.clone(),
         }
     }
+
+    /// List objects of a bucket like `list_prefix`, but split the keyspace into partitions
+    /// according to `partitioning`, and list up to `copy_parallelization` partitions at the same
+    /// time.
+    ///
+    /// The pages of all partitions are merged into one stream, in no particular order.
+    pub fn list_prefix_partitioned(
+        &self,
+        bucket: String,
+        prefix: Option<String>,
+        partitioning: Partitioning,
+    ) -> ListObjects<impl Stream<Item = Result<ListObjectsV2Output, Error>> + Sized + Send> {
+        let prefix = prefix.unwrap_or_default();
+        let parallelization = self.config.copy_parallelization.max(1);
+        let (algo, bucket2) = (self.clone(), bucket.clone());
+        let partitions = match partitioning {
+            Partitioning::CommonPrefixes => self
+                .s3
+                .list_objects_v2()
+                .bucket(bucket.clone())
+                .prefix(prefix.clone())
+                .delimiter("/")
+                .into_paginator()
+                .send()
+                .map_err(|source| Error::ListObjectsV2 { source })
+                // Each page of the delimited listing starts the partitions it discovers, before
+                // the rest is listed. The objects directly under `prefix` in the page (at most
+                // 1000) form a partition of their own.
+                .map(move |page| {
+                    let page = match page {
+                        Ok(page) => page,
+                        Err(e) => return stream::iter(vec![stream::once(future::err(e)).boxed()]),
+                    };
+                    let top = ListObjectsV2Output::builder()
+                        .set_contents(page.contents)
+                        .build();
+                    let mut partitions = vec![stream::once(ok(top)).boxed()];
+                    partitions.extend(
+                        page.common_prefixes
+                            .unwrap_or_default()
+                            .into_iter()
+                            .filter_map(|common_prefix| common_prefix.prefix)
+                            .map(|prefix| algo.list_range(bucket2.clone(), prefix, None, None)),
+                    );
+                    stream::iter(partitions)
+                })
+                .flatten()
+                .boxed(),
+            Partitioning::SplitPoints(mut points) => {
+                points.sort();
+                points.dedup();
+                let starts = std::iter::once(None).chain(points.clone().into_iter().map(Some));
+                let ends = points.into_iter().map(Some).chain(std::iter::once(None));
+                let partitions = starts
+                    .zip(ends)
+                    .map(|(start_after, end)| {
+                        algo.list_range(bucket2.clone(), prefix.clone(), start_after, end)
+                    })
+                    .collect::<Vec<_>>();
+                stream::iter(partitions).boxed()
+            }
+        };
+        let stream = merge_bounded(partitions, parallelization);
+
+        ListObjects {
+            s3: self.s3.clone(),
+            config: self.config.clone(),
+            stream,
+            bucket,
+            prefix,
+            encryption: self.encryption.clone(),
+        }
+    }
+
+    /// List the keys under `prefix` after `start_after` (exclusive) up to `end` (inclusive).
+    fn list_range(
+        &self,
+        bucket: String,
+        prefix: String,
+        start_after: Option<String>,
+        end: Option<String>,
+    ) -> BoxStream<'static, Result<ListObjectsV2Output, Error>> {
+        let pages = self
+            .s3
+            .list_objects_v2()
+            .bucket(bucket)
+            .prefix(prefix)
+            .set_start_after(start_after)
+            .into_paginator()
+            .send()
+            .map_err(|source| Error::ListObjectsV2 { source });
+        match end {
+            None => pages.boxed(),
+            Some(end) => pages
+                // Keys are listed in order, so we are done after the first page that goes past
+                // `end`
+                .scan(false, move |done, page| {
+                    if *done {
+                        return future::ready(None);
+                    }
+                    let page = page.map(|mut page| {
+                        if let Some(objects) = page.contents.as_mut() {
+                            let len = objects.len();
+                            objects.retain(|obj| {
+                                obj.key.as_deref().is_some_and(|key| key <= end.as_str())
+                            });
+                            *done = objects.len() < len;
+                        }
+                        page
+                    });
+                    future::ready(Some(page))
+                })
+                .boxed(),
+        }
+    }
+}
+
+/// Options for `S3Algo::list_prefix_with`.
+#[derive(Clone, Debug, Default)]
+pub struct ListOptions {
+    /// Start listing after this key.
+    pub start_after: Option<String>,
+    /// Maximum number of keys per page (at most 1000, which is also the default).
+    pub max_keys: Option<i32>,
+    /// Include the owner of each object in the listing.
+    pub fetch_owner: bool,
+    /// Resume a listing from the `next_continuation_token` of one of its pages.
+    pub continuation_token: Option<String>,
+}
+
+/// How `S3Algo::list_prefix_partitioned` splits the keyspace into partitions.
+#[derive(Clone, Debug)]
+pub enum Partitioning {
+    /// One partition per common prefix (up to the next `/`) directly under the listed prefix,
+    /// discovered with a delimited listing. Works well when the keys are spread over many
+    /// "directories", and the listed prefix ends with `/`.
+    CommonPrefixes,
+    /// Split the sorted keyspace at these keys. Each partition lists from one split point
+    /// (exclusive) to the next (inclusive) using `start_after`. Use `split_points` to choose them
+    /// from a sample of keys, for example from an earlier listing or an S3 Inventory.
+    SplitPoints(Vec<String>),
+}
+
+/// Choose split points for `Partitioning::SplitPoints` that divide the keys of `sample` into
+/// `n_partitions` partitions of about the same size.
+pub fn split_points(sample: &[String], n_partitions: usize) -> Vec<String> {
+    let mut sample = sample.to_vec();
+    sample.sort();
+    sample.dedup();
+    if sample.is_empty() {
+        return vec![];
+    }
+    let mut points = (1..n_partitions)
+        .map(|i| sample[i * sample.len() / n_partitions].clone())
+        .collect::<Vec<_>>();
+    points.dedup();
+    points
+}
+
+/// Merge the streams of `streams` into one stream, polling at most `n` of them at the same time.
+/// Streams are started as they arrive, while earlier ones are still running.
+fn merge_bounded<T: Send + 'static>(
+    streams: BoxStream<'static, BoxStream<'static, T>>,
+    n: usize,
+) -> impl Stream<Item = T> + Send {
+    let mut streams = streams.fuse();
+    let mut running = stream::SelectAll::new();
+    stream::poll_fn(move |cx| loop {
+        let mut streams_pending = false;
+        while running.len() < n && !streams.is_terminated() {
+            match streams.poll_next_unpin(cx) {
+                Poll::Ready(Some(stream)) => running.push(stream),
+                Poll::Ready(None) => {}
+                Poll::Pending => {
+                    streams_pending = true;
+                    break;
+                }
+            }
+        }
+        match running.poll_next_unpin(cx) {
+            Poll::Ready(Some(item)) => return Poll::Ready(Some(item)),
+            Poll::Ready(None) if streams.is_terminated() => return Poll::Ready(None),
+            Poll::Ready(None) if streams_pending => return Poll::Pending,
+            Poll::Pending if streams_pending || streams.is_terminated() || running.len() >= n => {
+                return Poll::Pending
+            }
+            // Streams finished, so there is room to start new ones
+            _ => {}
+        }
+    })
 }
 
 #[cfg(test)]
@@ -416,6 +1003,7 @@ mod test {
     use super::*;
     use crate::test::rand_string;
     use std::sync::atomic::{AtomicUsize, Ordering};
+    use tempdir::TempDir;
     #[tokio::test]
     async fn test_s3_delete_files_progress() {
         // Minio does paging at 10'000 fles, so we need more than that.
@@ -516,4 +1104,283 @@ mod test {
 
         assert_eq!(count, 0);
     }
+
+    #[tokio::test]
+    async fn test_s3_download_all_to_tar() {
+        let algo = S3Algo::new(testing_sdk_client().await);
+        let dir = rand_string(14);
+        let files = (0..20).map({
+            let dir = dir.clone();
+            move |i| ObjectSource::data(vec![i as u8; i * 1000], format!("{}/{:02}", dir, i))
+        });
+        algo.upload_files(
+            "test-bucket".into(),
+            files,
+            |_| async {},
+            |client| client.put_object(),
+        )
+        .await
+        .unwrap();
+
+        let prefix = format!("{}/", dir);
+        let archive = algo
+            .list_prefix("test-bucket".into(), Some(prefix.clone()))
+            .download_all_to_tar(Vec::<u8>::new(), move |key| {
+                key.trim_start_matches(&prefix).to_owned()
+            })
+            .await
+            .unwrap();
+
+        let mut archive = tar::Archive::new(&archive[..]);
+        for (i, entry) in archive.entries().unwrap().enumerate() {
+            let mut entry = entry.unwrap();
+            assert_eq!(entry.path().unwrap().to_str().unwrap(), format!("{:02}", i));
+            let mut contents = vec![];
+            std::io::Read::read_to_end(&mut entry, &mut contents).unwrap();
+            assert_eq!(contents, vec![i as u8; i * 1000]);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_s3_download_all_to_dir() {
+        let algo = S3Algo::new(testing_sdk_client().await);
+        let src = TempDir::new("s3-testing").unwrap();
+        let names = [
+            Path::new("100%.txt").to_owned(),
+            Path::new("sub").join("ø.txt"),
+        ];
+        for name in &names {
+            let path = src.path().join(name);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(&path, name.to_string_lossy().as_bytes()).unwrap();
+        }
+        let dir = rand_string(14);
+        let mapping = KeyMapping::default().invalid_names(InvalidNames::PercentEncode);
+        let files = FilesRecursive::new(src.path().to_owned(), dir.clone().into())
+            .key_mapping(mapping.clone())
+            .build()
+            .unwrap();
+        algo.try_upload_files(
+            "test-bucket".into(),
+            files,
+            |_| async {},
+            |client| client.put_object(),
+        )
+        .await
+        .unwrap();
+        // A folder marker, as created by the S3 console
+        algo.upload_files(
+            "test-bucket".into(),
+            std::iter::once(ObjectSource::data(vec![], format!("{}/sub/", dir))),
+            |_| async {},
+            |client| client.put_object(),
+        )
+        .await
+        .unwrap();
+
+        let dest = TempDir::new("s3-testing").unwrap();
+        // The `/` after the prefix is stripped too
+        algo.list_prefix("test-bucket".into(), Some(dir))
+            .download_all_to_dir(dest.path().to_owned(), mapping)
+            .await
+            .unwrap();
+        for name in &names {
+            let contents = std::fs::read(dest.path().join(name)).unwrap();
+            assert_eq!(contents, name.to_string_lossy().as_bytes());
+        }
+    }
+
+    #[test]
+    fn dir_paths() {
+        let mapping = KeyMapping::default();
+        let dest = Path::new("dest");
+        let path = |prefix, key| dir_path(dest, prefix, key, &mapping).unwrap();
+        assert_eq!(path("dir/", "dir/a/b"), Some(dest.join("a").join("b")));
+        // The `/` after a prefix without trailing slash is stripped too
+        assert_eq!(path("dir", "dir/a/b"), Some(dest.join("a").join("b")));
+        assert_eq!(path("", "dir/a"), Some(dest.join("dir").join("a")));
+        // Folder markers are skipped
+        assert_eq!(path("dir/", "dir/"), None);
+        assert_eq!(path("dir", "dir/a/"), None);
+        assert!(dir_path(dest, "dir/", "dir/../a", &mapping).is_err());
+    }
+
+    #[test]
+    fn tar_paths() {
+        assert!(is_tar_path("a/b"));
+        assert!(is_tar_path("a/..b"));
+        assert!(!is_tar_path(""));
+        assert!(!is_tar_path("/a"));
+        assert!(!is_tar_path("a/../b"));
+    }
+
+    async fn keys<S>(listing: ListObjects<S>) -> Vec<String>
+    where
+        S: Stream<Item = Result<ListObjectsV2Output, Error>> + Sized + Send + 'static,
+    {
+        listing
+            .flatten()
+            .map_ok(|obj| obj.key.unwrap())
+            .try_collect()
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn filters() {
+        let object = |key: &str, size, modified, class| {
+            Object::builder()
+                .key(key)
+                .size(size)
+                .last_modified(DateTime::from_secs(modified))
+                .storage_class(class)
+                .build()
+        };
+        let pages = vec![
+            vec![
+                object("logs/a.gz", 10, 100, ObjectStorageClass::Standard),
+                object("logs/b.txt", 20, 200, ObjectStorageClass::Glacier),
+            ],
+            vec![],
+            vec![
+                object("logs/c.gz", 30, 300, ObjectStorageClass::Standard),
+                object("other/d.gz", 40, 400, ObjectStorageClass::Standard),
+            ],
+        ];
+        let listing = ListObjects::from_pages("", pages.clone()).await;
+        assert_eq!(
+            keys(listing.filter_key_glob("logs/*.gz").unwrap()).await,
+            vec!["logs/a.gz", "logs/c.gz"]
+        );
+        let listing = ListObjects::from_pages("", pages.clone()).await;
+        assert_eq!(
+            keys(listing.filter_key_regex(r"^[^/]+/[bd]\.").unwrap()).await,
+            vec!["logs/b.txt", "other/d.gz"]
+        );
+        let listing = ListObjects::from_pages("", pages.clone()).await;
+        assert_eq!(
+            keys(
+                listing
+                    .filter_size(15..=30)
+                    .modified_after(DateTime::from_secs(250))
+            )
+            .await,
+            vec!["logs/c.gz"]
+        );
+        let listing = ListObjects::from_pages("", pages.clone()).await;
+        assert_eq!(
+            keys(
+                listing
+                    .filter_storage_class(ObjectStorageClass::Standard)
+                    .modified_before(DateTime::from_secs(400))
+            )
+            .await,
+            vec!["logs/a.gz", "logs/c.gz"]
+        );
+        let listing = ListObjects::from_pages("", pages).await;
+        assert!(listing.filter_key_glob("[").is_err());
+    }
+
+    #[test]
+    fn copy_source_encoding() {
+        assert_eq!(
+            copy_source("bucket", "dir/a b+c%.txt", None),
+            "bucket/dir/a%20b%2Bc%25.txt"
+        );
+        assert_eq!(
+            copy_source("bucket", "ø", Some("v1")),
+            "bucket/%C3%B8?versionId=v1"
+        );
+    }
+
+    #[test]
+    fn choose_split_points() {
+        let sample = (0..10).map(|i| format!("k{}", i)).rev().collect::<Vec<_>>();
+        assert_eq!(split_points(&sample, 2), vec!["k5"]);
+        assert_eq!(split_points(&sample, 4), vec!["k2", "k5", "k7"]);
+        assert_eq!(split_points(&sample[..2], 8), vec!["k8", "k9"]);
+        assert!(split_points(&[], 4).is_empty());
+        assert!(split_points(&sample, 1).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_s3_list_prefix_partitioned() {
+        let algo = S3Algo::new(testing_sdk_client().await);
+        let dir = format!("{}/", rand_string(14));
+        let mut expected = (0..40)
+            .map(|i| format!("{}{}/{}", dir, i % 4, i))
+            .chain(Some(format!("{}top", dir)))
+            .collect::<Vec<_>>();
+        let files = expected
+            .iter()
+            .map(|key| ObjectSource::data(vec![1], key.clone()))
+            .collect::<Vec<_>>();
+        algo.upload_files(
+            "test-bucket".into(),
+            files.into_iter(),
+            |_| async {},
+            |client| client.put_object(),
+        )
+        .await
+        .unwrap();
+        expected.sort();
+
+        let points = split_points(&expected, 3);
+        for partitioning in [
+            Partitioning::CommonPrefixes,
+            Partitioning::SplitPoints(points),
+        ] {
+            let listing =
+                algo.list_prefix_partitioned("test-bucket".into(), Some(dir.clone()), partitioning);
+            let mut listed = keys(listing).await;
+            listed.sort();
+            assert_eq!(listed, expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_s3_list_prefix_with_options() {
+        let algo = S3Algo::new(testing_sdk_client().await);
+        let dir = format!("{}/", rand_string(14));
+        let expected = (0..10).map(|i| format!("{}{}", dir, i)).collect::<Vec<_>>();
+        let files = expected
+            .iter()
+            .map(|key| ObjectSource::data(vec![1], key.clone()))
+            .collect::<Vec<_>>();
+        algo.upload_files(
+            "test-bucket".into(),
+            files.into_iter(),
+            |_| async {},
+            |client| client.put_object(),
+        )
+        .await
+        .unwrap();
+
+        let listing = algo.list_prefix_with(
+            "test-bucket".into(),
+            Some(dir.clone()),
+            ListOptions {
+                start_after: Some(format!("{}2", dir)),
+                ..Default::default()
+            },
+        );
+        assert_eq!(listing.prefix(), dir);
+        assert_eq!(keys(listing).await, expected[3..]);
+
+        // Stop after the first page, and resume from its continuation token
+        let options = ListOptions {
+            max_keys: Some(4),
+            ..Default::default()
+        };
+        let mut listing =
+            algo.list_prefix_with("test-bucket".into(), Some(dir.clone()), options.clone());
+        let page = listing.next().await.unwrap().unwrap();
+        assert_eq!(page.contents.unwrap().len(), 4);
+        let options = ListOptions {
+            continuation_token: page.next_continuation_token,
+            ..options
+        };
+        let listing = algo.list_prefix_with("test-bucket".into(), Some(dir.clone()), options);
+        assert_eq!(keys(listing).await, expected[4..]);
+    }
 }
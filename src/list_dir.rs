@@ -0,0 +1,150 @@
+//! Hierarchical listing, treating `/` in keys as a directory separator.
+use super::*;
+use aws_sdk_s3::types::Object;
+use futures::future::BoxFuture;
+use futures::stream::FuturesUnordered;
+use std::collections::VecDeque;
+
+/// The contents of a "directory" in S3: all keys that start with `prefix` and have no further
+/// `/` after it.
+#[derive(Clone, Debug, Default)]
+pub struct DirListing {
+    /// The listed prefix. Ends with `/` unless it is the root of the bucket (`""`).
+    pub prefix: String,
+    /// Objects directly under `prefix`.
+    pub objects: Vec<Object>,
+    /// Subdirectories of `prefix`: the common prefixes of the remaining keys, each ending with
+    /// `/`.
+    pub common_prefixes: Vec<String>,
+}
+
+impl DirListing {
+    /// Total size in bytes of the objects directly under `prefix` (not in subdirectories).
+    pub fn size(&self) -> i64 {
+        self.objects.iter().map(|obj| obj.size).sum()
+    }
+}
+
+impl S3Algo {
+    /// List the objects and subdirectories directly under `prefix`, using `/` as delimiter.
+    /// `prefix` should end with `/` (or be empty to list the root of the bucket).
+    pub async fn list_dir(&self, bucket: String, prefix: String) -> Result<DirListing, Error> {
+        let dir = DirListing {
+            prefix: prefix.clone(),
+            ..Default::default()
+        };
+        self.s3
+            .list_objects_v2()
+            .bucket(bucket)
+            .prefix(prefix)
+            .delimiter("/")
+            .into_paginator()
+            .send()
+            .map_err(|source| Error::ListObjectsV2 { source })
+            .try_fold(dir, |mut dir, response| async move {
+                dir.objects.extend(response.contents.unwrap_or_default());
+                dir.common_prefixes.extend(
+                    response
+                        .common_prefixes
+                        .unwrap_or_default()
+                        .into_iter()
+                        .filter_map(|common_prefix| common_prefix.prefix),
+                );
+                Ok(dir)
+            })
+            .await
+    }
+
+    /// Walk the tree of directories under `prefix` (see `list_dir`), listing up to
+    /// `copy_parallelization` directories at the same time.
+    ///
+    /// Yields the listing of every directory, including `prefix` itself, in no particular order.
+    /// A failed listing is yielded as an error, and the walk continues with the other directories.
+    pub fn walk_dirs(
+        &self,
+        bucket: String,
+        prefix: String,
+    ) -> impl Stream<Item = Result<DirListing, Error>> + Send {
+        let algo = self.clone();
+        let parallelization = self.config.copy_parallelization.max(1);
+        let state = WalkState {
+            pending: vec![prefix].into(),
+            running: FuturesUnordered::new(),
+        };
+        stream::unfold(state, move |mut state| {
+            let (algo, bucket) = (algo.clone(), bucket.clone());
+            async move {
+                while state.running.len() < parallelization {
+                    match state.pending.pop_front() {
+                        Some(prefix) => {
+                            let (algo, bucket) = (algo.clone(), bucket.clone());
+                            state
+                                .running
+                                .push(async move { algo.list_dir(bucket, prefix).await }.boxed());
+                        }
+                        None => break,
+                    }
+                }
+                let result = state.running.next().await?;
+                if let Ok(dir) = &result {
+                    state.pending.extend(dir.common_prefixes.iter().cloned());
+                }
+                Some((result, state))
+            }
+        })
+    }
+}
+
+struct WalkState {
+    /// Directories that are discovered but not yet listed
+    pending: VecDeque<String>,
+    running: FuturesUnordered<BoxFuture<'static, Result<DirListing, Error>>>,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::rand_string;
+    use std::collections::HashMap;
+
+    #[tokio::test]
+    async fn test_s3_list_dir_and_walk() {
+        let algo = S3Algo::new(testing_sdk_client().await);
+        let root = format!("{}/", rand_string(14));
+        let keys = ["a", "b/c", "b/d", "b/e/f", "g/h"];
+        let files = keys
+            .iter()
+            .map(|key| ObjectSource::data(vec![0; 10], format!("{}{}", root, key)))
+            .collect::<Vec<_>>();
+        algo.upload_files(
+            "test-bucket".into(),
+            files.into_iter(),
+            |_| async {},
+            |client| client.put_object(),
+        )
+        .await
+        .unwrap();
+
+        let dir = algo
+            .list_dir("test-bucket".into(), root.clone())
+            .await
+            .unwrap();
+        assert_eq!(dir.objects.len(), 1);
+        assert_eq!(
+            dir.common_prefixes,
+            vec![format!("{}b/", root), format!("{}g/", root)]
+        );
+
+        let sizes = algo
+            .walk_dirs("test-bucket".into(), root.clone())
+            .map_ok(|dir| (dir.prefix.trim_start_matches(&root).to_owned(), dir.size()))
+            .try_collect::<HashMap<_, _>>()
+            .await
+            .unwrap();
+        assert_eq!(sizes.len(), 4);
+        assert_eq!(sizes[""], 10);
+        assert_eq!(sizes["b/"], 20);
+        assert_eq!(sizes["b/e/"], 10);
+        assert_eq!(sizes["g/"], 10);
+    }
+}
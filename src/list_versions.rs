@@ -0,0 +1,764 @@
+//! Listing and permanent deletion of object versions in versioned buckets.
+use super::*;
+use crate::copy::{copy_object, CopySource};
+use crate::list_actions::delete_identifiers;
+use aws_sdk_s3::operation::list_object_versions::ListObjectVersionsOutput;
+use aws_sdk_s3::primitives::DateTime;
+use aws_sdk_s3::types::{DeleteMarkerEntry, ObjectIdentifier, ObjectVersion};
+use futures::stream::BoxStream;
+
+/// A version of an object, or a delete marker, as listed by `S3Algo::list_versions`.
+#[derive(Clone, Debug)]
+pub enum VersionEntry {
+    Version(ObjectVersion),
+    DeleteMarker(DeleteMarkerEntry),
+}
+
+impl VersionEntry {
+    pub fn key(&self) -> Option<&str> {
+        match self {
+            Self::Version(version) => version.key.as_deref(),
+            Self::DeleteMarker(marker) => marker.key.as_deref(),
+        }
+    }
+    pub fn version_id(&self) -> Option<&str> {
+        match self {
+            Self::Version(version) => version.version_id.as_deref(),
+            Self::DeleteMarker(marker) => marker.version_id.as_deref(),
+        }
+    }
+    /// Whether this is the current version of the key.
+    pub fn is_latest(&self) -> bool {
+        match self {
+            Self::Version(version) => version.is_latest,
+            Self::DeleteMarker(marker) => marker.is_latest,
+        }
+    }
+    pub fn last_modified(&self) -> Option<DateTime> {
+        match self {
+            Self::Version(version) => version.last_modified,
+            Self::DeleteMarker(marker) => marker.last_modified,
+        }
+    }
+    pub fn is_delete_marker(&self) -> bool {
+        matches!(self, Self::DeleteMarker(_))
+    }
+}
+
+/// Versions and delete markers of one page, ordered like S3 orders them: by key, and from the
+/// newest to the oldest version of each key.
+///
+/// S3 returns both lists in that order, so they are merged, keeping the order within each list.
+/// Modification times have a precision of seconds, so they only decide between a version and a
+/// delete marker of the same key; on a tie, the version comes first.
+fn sorted_entries(output: ListObjectVersionsOutput) -> Vec<VersionEntry> {
+    let mut versions = output
+        .versions
+        .unwrap_or_default()
+        .into_iter()
+        .map(VersionEntry::Version)
+        .peekable();
+    let mut markers = output
+        .delete_markers
+        .unwrap_or_default()
+        .into_iter()
+        .map(VersionEntry::DeleteMarker)
+        .peekable();
+    let mut entries = vec![];
+    loop {
+        let next = match (versions.peek(), markers.peek()) {
+            (Some(version), Some(marker)) => {
+                let marker_first = marker
+                    .key()
+                    .cmp(&version.key())
+                    .then(version.is_latest().cmp(&marker.is_latest()))
+                    .then(version.last_modified().cmp(&marker.last_modified()))
+                    == std::cmp::Ordering::Less;
+                if marker_first {
+                    markers.next()
+                } else {
+                    versions.next()
+                }
+            }
+            _ => versions.next().or_else(|| markers.next()),
+        };
+        match next {
+            Some(entry) => entries.push(entry),
+            None => return entries,
+        }
+    }
+}
+
+/// A stream of pages of object versions and delete markers (see `S3Algo::list_versions`), that
+/// can be filtered, and whose versions can be permanently deleted.
+///
+/// Each page is ordered by key, and from the newest to the oldest version of each key.
+pub struct ListVersions<S> {
+    s3: Client,
+    config: Config,
+    bucket: String,
+    /// Common prefix (as requested) of the listed objects. Empty string if all objects were
+    /// requested.
+    prefix: String,
+    stream: S,
+}
+
+impl<S> ListVersions<S>
+where
+    S: Stream<Item = Result<Vec<VersionEntry>, Error>> + Sized + Send + 'static,
+{
+    pub fn boxed(self) -> ListVersions<BoxStream<'static, Result<Vec<VersionEntry>, Error>>> {
+        self.map_stream(|stream| stream.boxed())
+    }
+
+    /// The bucket of the listed versions.
+    pub fn bucket(&self) -> &str {
+        &self.bucket
+    }
+
+    /// Common prefix (as requested) of the listed objects.
+    pub fn prefix(&self) -> &str {
+        &self.prefix
+    }
+
+    fn map_stream<F, T>(self, f: F) -> ListVersions<T>
+    where
+        F: FnOnce(S) -> T,
+    {
+        ListVersions {
+            s3: self.s3,
+            config: self.config,
+            bucket: self.bucket,
+            prefix: self.prefix,
+            stream: f(self.stream),
+        }
+    }
+
+    /// Keep only the entries for which `predicate` returns `true`.
+    pub fn filter<P>(
+        self,
+        predicate: P,
+    ) -> ListVersions<impl Stream<Item = Result<Vec<VersionEntry>, Error>> + Sized + Send>
+    where
+        P: Fn(&VersionEntry) -> bool + Send + Sync + 'static,
+    {
+        self.map_stream(|stream| {
+            stream.map_ok(move |entries| {
+                entries
+                    .into_iter()
+                    .filter(|entry| predicate(entry))
+                    .collect()
+            })
+        })
+    }
+
+    /// Keep only versions and delete markers that are not current.
+    pub fn non_current(
+        self,
+    ) -> ListVersions<impl Stream<Item = Result<Vec<VersionEntry>, Error>> + Sized + Send> {
+        self.filter(|entry| !entry.is_latest())
+    }
+
+    /// Keep only entries that were last modified before `time`.
+    pub fn modified_before<T: Into<DateTime>>(
+        self,
+        time: T,
+    ) -> ListVersions<impl Stream<Item = Result<Vec<VersionEntry>, Error>> + Sized + Send> {
+        let time = time.into();
+        self.filter(move |entry| {
+            entry
+                .last_modified()
+                .is_some_and(|modified| modified < time)
+        })
+    }
+
+    /// Keep only non-current entries that stopped being current before `time`, that is, whose
+    /// next newer version (or delete marker) was created before `time`. This is how S3
+    /// lifecycle rules age non-current versions: keeping the versions that stopped being current
+    /// more than 30 days ago would be `noncurrent_before(now - 30 days)`.
+    ///
+    /// The newer version is found among the listed entries, so apply this before other filters.
+    pub fn noncurrent_before<T: Into<DateTime>>(
+        self,
+        time: T,
+    ) -> ListVersions<impl Stream<Item = Result<Vec<VersionEntry>, Error>> + Sized + Send> {
+        let time = time.into();
+        self.map_stream(|stream| {
+            // The key and modification time of the previous (newer) entry, which may be on the
+            // previous page.
+            stream.scan(None::<(String, Option<DateTime>)>, move |newer, page| {
+                let page = page.map(|entries| {
+                    entries
+                        .into_iter()
+                        .filter(|entry| {
+                            let superseded = match (newer.as_ref(), entry.key()) {
+                                (Some((newer_key, modified)), Some(key)) if newer_key == key => {
+                                    *modified
+                                }
+                                _ => None,
+                            };
+                            *newer = entry
+                                .key()
+                                .map(|key| (key.to_owned(), entry.last_modified()));
+                            !entry.is_latest() && superseded.is_some_and(|t| t < time)
+                        })
+                        .collect()
+                });
+                future::ready(Some(page))
+            })
+        })
+    }
+
+    /// Flatten into a stream of entries.
+    pub fn flatten(self) -> impl Stream<Item = Result<VersionEntry, Error>> {
+        self.stream
+            .map_ok(|entries| stream::iter(entries).map(Ok))
+            .try_flatten()
+    }
+
+    /// Permanently delete all listed versions and delete markers.
+    ///
+    /// The progress closures are called like in `ListObjects::delete_all`: `list_progress` with
+    /// the number of entries of each listed page, and `delete_progress` with the `RequestReport`
+    /// of each delete request, where `size` is the number of deleted entries.
+    pub fn delete_all<P1, P2, F1, F2>(
+        self,
+        list_progress: P1,
+        delete_progress: P2,
+    ) -> impl Future<Output = Result<(), Error>>
+    where
+        P1: Fn(usize) -> F1 + Clone + Send + Sync + 'static,
+        P2: Fn(RequestReport) -> F2 + Clone + Send + Sync + 'static,
+        F1: Future<Output = ()> + Send + 'static,
+        F2: Future<Output = ()> + Send + 'static,
+    {
+        let ListVersions {
+            s3,
+            config,
+            bucket,
+            stream,
+            prefix: _,
+        } = self;
+        let timeout = Arc::new(Mutex::new(TimeoutState::new(
+            config.algorithm.clone(),
+            config.delete_requests.clone(),
+        )));
+        let n_retries = config.algorithm.n_retries;
+        stream.try_for_each_concurrent(None, move |entries| {
+            let (s3, bucket, timeout, delete_progress, list_progress) = (
+                s3.clone(),
+                bucket.clone(),
+                timeout.clone(),
+                delete_progress.clone(),
+                list_progress.clone(),
+            );
+            let objects = entries
+                .iter()
+                .filter_map(|entry| {
+                    entry.key().map(|key| {
+                        ObjectIdentifier::builder()
+                            .key(key)
+                            .set_version_id(entry.version_id().map(String::from))
+                            .build()
+                    })
+                })
+                .collect::<Vec<_>>();
+            delete_identifiers(
+                s3,
+                bucket,
+                objects,
+                n_retries,
+                timeout,
+                list_progress,
+                delete_progress,
+            )
+        })
+    }
+}
+
+impl S3Algo {
+    /// List all versions and delete markers of the objects in a versioned bucket.
+    pub fn list_versions(
+        &self,
+        bucket: String,
+        prefix: Option<String>,
+    ) -> ListVersions<impl Stream<Item = Result<Vec<VersionEntry>, Error>> + Sized + Send> {
+        let (s3, bucket2, prefix2) = (self.s3.clone(), bucket.clone(), prefix.clone());
+        // ListObjectVersions has no paginator, so we follow the markers ourselves. The state is
+        // `None` after the last page.
+        let stream = stream::try_unfold(Some((None::<String>, None::<String>)), move |markers| {
+            let (s3, bucket, prefix) = (s3.clone(), bucket2.clone(), prefix2.clone());
+            async move {
+                let (key_marker, version_id_marker) = match markers {
+                    Some(markers) => markers,
+                    None => return Ok(None),
+                };
+                let output = s3
+                    .list_object_versions()
+                    .bucket(bucket)
+                    .set_prefix(prefix)
+                    .set_key_marker(key_marker)
+                    .set_version_id_marker(version_id_marker)
+                    .send()
+                    .await
+                    .context(err::ListObjectVersions)?;
+                let next = if output.is_truncated {
+                    Some((
+                        output.next_key_marker.clone(),
+                        output.next_version_id_marker.clone(),
+                    ))
+                } else {
+                    None
+                };
+                Ok(Some((sorted_entries(output), next)))
+            }
+        });
+        ListVersions {
+            s3: self.s3.clone(),
+            config: self.config.clone(),
+            bucket,
+            prefix: prefix.unwrap_or_default(),
+            stream,
+        }
+    }
+}
+
+/// What to do with a key to restore it to a point in time.
+#[derive(Debug, PartialEq)]
+enum Restore {
+    /// Copy an old version onto its key.
+    Copy(Box<ObjectVersion>),
+    /// Delete the key, which did not exist at the time.
+    Delete { key: String },
+}
+
+/// Find what to do to restore a key to `time`, given all its versions and delete markers from
+/// newest to oldest.
+fn restore_action(entries: &[VersionEntry], time: DateTime) -> Option<Restore> {
+    let current = entries.iter().find(|entry| entry.is_latest());
+    let target = entries.iter().find(|entry| {
+        entry
+            .last_modified()
+            .is_some_and(|modified| modified <= time)
+    });
+    match (current, target) {
+        (Some(current), Some(target)) if current.version_id() == target.version_id() => None,
+        (_, Some(VersionEntry::Version(version))) => Some(Restore::Copy(Box::new(version.clone()))),
+        // The key did not exist, or was deleted, at the time
+        (Some(VersionEntry::Version(version)), _) => Some(Restore::Delete {
+            key: version.key.clone()?,
+        }),
+        _ => None,
+    }
+}
+
+/// Copy a version of an object onto the same key, making it the current version. Versions larger
+/// than 5 GiB are copied in parts.
+async fn copy_version(
+    s3: Client,
+    encryption: Encryption,
+    bucket: String,
+    version: ObjectVersion,
+    n_retries: usize,
+    timeout: Arc<Mutex<TimeoutState>>,
+) -> Result<RequestReport, Error> {
+    let (key, version_id) = match (version.key, version.version_id) {
+        (Some(key), Some(version_id)) => (key, version_id),
+        _ => return Err(Error::MissingKeyOrVersion),
+    };
+    let request = s3.copy_object().bucket(&bucket).key(&key);
+    let source = CopySource {
+        bucket,
+        key,
+        version_id: Some(version_id),
+        size: version.size,
+    };
+    copy_object(s3, encryption, source, request, n_retries, timeout).await
+}
+
+impl S3Algo {
+    /// Restore all keys under `prefix` in a versioned bucket to the state they had at `time`.
+    ///
+    /// For each key, the version that was current at `time` is copied onto the key, unless it
+    /// is still the current version. Keys that did not exist at `time` (or were deleted) are
+    /// deleted, which only adds a delete marker, so the restore can itself be undone.
+    ///
+    /// `list_progress` is called with the number of versions and delete markers of each listed
+    /// page. `restore_progress` is called with the `RequestReport` of every copy request (where
+    /// `size` is in bytes), and of every delete request (where `size` is the number of deleted
+    /// keys), like in `ListObjects::delete_all`. Versions larger than 5 GiB are copied in parts,
+    /// with a single report for the whole copy.
+    pub fn restore_to_time<T, P1, P2, F1, F2>(
+        &self,
+        bucket: String,
+        prefix: Option<String>,
+        time: T,
+        list_progress: P1,
+        restore_progress: P2,
+    ) -> impl Future<Output = Result<(), Error>>
+    where
+        T: Into<DateTime>,
+        P1: Fn(usize) -> F1 + Clone + Send + Sync + 'static,
+        P2: Fn(RequestReport) -> F2 + Clone + Send + Sync + 'static,
+        F1: Future<Output = ()> + Send + 'static,
+        F2: Future<Output = ()> + Send + 'static,
+    {
+        let time = time.into();
+        let (s3, encryption, config) = (
+            self.s3.clone(),
+            self.encryption.clone(),
+            self.config.clone(),
+        );
+        let delete_timeout = Arc::new(Mutex::new(TimeoutState::new(
+            config.algorithm.clone(),
+            config.delete_requests.clone(),
+        )));
+        let copy_timeout = Arc::new(Mutex::new(TimeoutState::new(
+            config.algorithm.clone(),
+            config.put_requests.clone(),
+        )));
+        let n_retries = config.algorithm.n_retries;
+
+        // The versions of a key may span several pages, so a key is only restored when the next
+        // key (or the end of the listing) is reached. `None` marks the end.
+        let pages = self
+            .list_versions(bucket.clone(), prefix)
+            .stream
+            .map(Some)
+            .chain(stream::once(future::ready(None)));
+        let actions = pages.scan(Vec::<VersionEntry>::new(), move |group, page| {
+            let actions = match page {
+                Some(Ok(entries)) => {
+                    let n_listed = entries.len();
+                    let mut actions = vec![];
+                    for entry in entries {
+                        if group
+                            .first()
+                            .is_some_and(|first| first.key() != entry.key())
+                        {
+                            actions.extend(restore_action(group, time));
+                            group.clear();
+                        }
+                        group.push(entry);
+                    }
+                    Ok((n_listed, actions))
+                }
+                Some(Err(e)) => Err(e),
+                None => Ok((0, restore_action(group, time).into_iter().collect())),
+            };
+            future::ready(Some(actions))
+        });
+
+        // Each page becomes one delete request for the keys to delete, and one copy request for
+        // each key to restore.
+        actions
+            .and_then(move |(n_listed, actions)| {
+                let (s3, encryption, bucket) = (s3.clone(), encryption.clone(), bucket.clone());
+                let (delete_timeout, copy_timeout) = (delete_timeout.clone(), copy_timeout.clone());
+                let (list_progress, restore_progress) =
+                    (list_progress.clone(), restore_progress.clone());
+                async move {
+                    list_progress(n_listed).await;
+                    let mut requests = vec![];
+                    let mut deletes = vec![];
+                    for action in actions {
+                        match action {
+                            Restore::Delete { key } => {
+                                deletes.push(ObjectIdentifier::builder().key(key).build())
+                            }
+                            Restore::Copy(version) => {
+                                let (s3, encryption, bucket, timeout, progress) = (
+                                    s3.clone(),
+                                    encryption.clone(),
+                                    bucket.clone(),
+                                    copy_timeout.clone(),
+                                    restore_progress.clone(),
+                                );
+                                requests.push(
+                                    async move {
+                                        let report = copy_version(
+                                            s3, encryption, bucket, *version, n_retries, timeout,
+                                        )
+                                        .await?;
+                                        progress(report).await;
+                                        Ok::<_, Error>(())
+                                    }
+                                    .boxed(),
+                                );
+                            }
+                        }
+                    }
+                    if !deletes.is_empty() {
+                        requests.push(
+                            delete_identifiers(
+                                s3,
+                                bucket,
+                                deletes,
+                                n_retries,
+                                delete_timeout,
+                                |_| async {},
+                                restore_progress,
+                            )
+                            .boxed(),
+                        );
+                    }
+                    Ok::<_, Error>(stream::iter(requests).map(Ok::<_, Error>))
+                }
+            })
+            .try_flatten()
+            .try_buffer_unordered(config.copy_parallelization)
+            .try_for_each(|_| async { Ok(()) })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::{rand_string, versioned_test_bucket};
+
+    async fn put(algo: &S3Algo, bucket: &str, key: &str, data: &str) {
+        algo.s3
+            .put_object()
+            .bucket(bucket)
+            .key(key)
+            .body(data.as_bytes().to_vec().into())
+            .send()
+            .await
+            .unwrap();
+    }
+
+    async fn entries<S>(listing: ListVersions<S>) -> Vec<(String, bool, bool)>
+    where
+        S: Stream<Item = Result<Vec<VersionEntry>, Error>> + Sized + Send + 'static,
+    {
+        listing
+            .flatten()
+            .map_ok(|entry| {
+                (
+                    entry.key().unwrap().to_owned(),
+                    entry.is_latest(),
+                    entry.is_delete_marker(),
+                )
+            })
+            .try_collect()
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_s3_list_and_delete_versions() {
+        let algo = S3Algo::new(testing_sdk_client().await);
+        let bucket = versioned_test_bucket(&algo.s3).await;
+        let dir = format!("{}/", rand_string(14));
+        let (a, b) = (format!("{}a", dir), format!("{}b", dir));
+        put(&algo, &bucket, &a, "1").await;
+        put(&algo, &bucket, &a, "2").await;
+        put(&algo, &bucket, &b, "1").await;
+        algo.s3
+            .delete_object()
+            .bucket(&bucket)
+            .key(&b)
+            .send()
+            .await
+            .unwrap();
+
+        let listed = entries(algo.list_versions(bucket.clone(), Some(dir.clone()))).await;
+        assert_eq!(
+            listed,
+            vec![
+                (a.clone(), true, false),
+                (a.clone(), false, false),
+                (b.clone(), true, true),
+                (b.clone(), false, false),
+            ]
+        );
+
+        // Nothing became non-current before the test started
+        let listing = algo
+            .list_versions(bucket.clone(), Some(dir.clone()))
+            .noncurrent_before(DateTime::from_secs(0));
+        assert!(entries(listing).await.is_empty());
+
+        algo.list_versions(bucket.clone(), Some(dir.clone()))
+            .non_current()
+            .delete_all(|_| async {}, |_| async {})
+            .await
+            .unwrap();
+        let listed = entries(algo.list_versions(bucket.clone(), Some(dir.clone()))).await;
+        assert_eq!(listed, vec![(a, true, false), (b, true, true)]);
+
+        algo.list_versions(bucket.clone(), Some(dir.clone()))
+            .delete_all(|_| async {}, |_| async {})
+            .await
+            .unwrap();
+        assert!(entries(algo.list_versions(bucket, Some(dir)))
+            .await
+            .is_empty());
+    }
+
+    fn version(id: &str, latest: bool, modified: i64) -> VersionEntry {
+        VersionEntry::Version(
+            ObjectVersion::builder()
+                .key("k")
+                .version_id(id)
+                .is_latest(latest)
+                .last_modified(DateTime::from_secs(modified))
+                .build(),
+        )
+    }
+
+    fn delete_marker(id: &str, latest: bool, modified: i64) -> VersionEntry {
+        VersionEntry::DeleteMarker(
+            DeleteMarkerEntry::builder()
+                .key("k")
+                .version_id(id)
+                .is_latest(latest)
+                .last_modified(DateTime::from_secs(modified))
+                .build(),
+        )
+    }
+
+    fn copied_version(action: Option<Restore>) -> Option<String> {
+        match action {
+            Some(Restore::Copy(version)) => version.version_id,
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn merge_versions_and_markers() {
+        let versions = vec![
+            ("a", "4", true, 200),
+            ("a", "3", false, 100),
+            ("a", "1", false, 100),
+            ("c", "1", true, 100),
+        ];
+        let markers = vec![("a", "2", false, 150), ("b", "1", true, 100)];
+        let output = ListObjectVersionsOutput::builder()
+            .set_versions(Some(
+                versions
+                    .into_iter()
+                    .map(|(key, id, latest, modified)| {
+                        ObjectVersion::builder()
+                            .key(key)
+                            .version_id(id)
+                            .is_latest(latest)
+                            .last_modified(DateTime::from_secs(modified))
+                            .build()
+                    })
+                    .collect(),
+            ))
+            .set_delete_markers(Some(
+                markers
+                    .into_iter()
+                    .map(|(key, id, latest, modified)| {
+                        DeleteMarkerEntry::builder()
+                            .key(key)
+                            .version_id(id)
+                            .is_latest(latest)
+                            .last_modified(DateTime::from_secs(modified))
+                            .build()
+                    })
+                    .collect(),
+            ))
+            .build();
+        let entries = sorted_entries(output)
+            .iter()
+            .map(|entry| format!("{}{}", entry.key().unwrap(), entry.version_id().unwrap()))
+            .collect::<Vec<_>>();
+        // Versions "3" and "1" of "a" are from the same second, and keep their order
+        assert_eq!(entries, vec!["a4", "a2", "a3", "a1", "b1", "c1"]);
+    }
+
+    #[test]
+    fn restore_actions() {
+        let at = |time| DateTime::from_secs(time);
+        let history = vec![
+            version("3", true, 300),
+            delete_marker("2", false, 200),
+            version("1", false, 100),
+        ];
+        // Current at the time: nothing to do
+        assert_eq!(restore_action(&history, at(350)), None);
+        // Deleted at the time
+        assert_eq!(
+            restore_action(&history, at(250)),
+            Some(Restore::Delete { key: "k".into() })
+        );
+        assert_eq!(
+            copied_version(restore_action(&history, at(150))),
+            Some("1".into())
+        );
+        // Did not exist yet
+        assert_eq!(
+            restore_action(&history, at(50)),
+            Some(Restore::Delete { key: "k".into() })
+        );
+
+        let deleted = vec![delete_marker("2", true, 200), version("1", false, 100)];
+        assert_eq!(
+            copied_version(restore_action(&deleted, at(150))),
+            Some("1".into())
+        );
+        assert_eq!(restore_action(&deleted, at(50)), None);
+    }
+
+    #[tokio::test]
+    async fn test_s3_restore_to_time() {
+        let algo = S3Algo::new(testing_sdk_client().await);
+        let bucket = versioned_test_bucket(&algo.s3).await;
+        let dir = format!("{}/", rand_string(14));
+        let key = |name| format!("{}{}", dir, name);
+        put(&algo, &bucket, &key("changed"), "old").await;
+        put(&algo, &bucket, &key("deleted"), "old").await;
+        put(&algo, &bucket, &key("unchanged"), "old").await;
+
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+        let time = std::time::SystemTime::now();
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+
+        put(&algo, &bucket, &key("changed"), "new").await;
+        put(&algo, &bucket, &key("created"), "new").await;
+        algo.s3
+            .delete_object()
+            .bucket(&bucket)
+            .key(key("deleted"))
+            .send()
+            .await
+            .unwrap();
+
+        let reports = Arc::new(std::sync::Mutex::new(vec![]));
+        let reports2 = reports.clone();
+        algo.restore_to_time(
+            bucket.clone(),
+            Some(dir.clone()),
+            time,
+            |_| async {},
+            move |report| {
+                reports2.lock().unwrap().push(report);
+                async {}
+            },
+        )
+        .await
+        .unwrap();
+        // Two copies and one delete request
+        assert_eq!(reports.lock().unwrap().len(), 3);
+
+        let listing = algo.list_prefix(bucket.clone(), Some(dir.clone()));
+        let mut contents = listing
+            .download_all_to_vec()
+            .map_ok(|(key, data)| (key, String::from_utf8(data).unwrap()))
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap();
+        contents.sort();
+        assert_eq!(
+            contents,
+            vec![
+                (key("changed"), "old".to_owned()),
+                (key("deleted"), "old".to_owned()),
+                (key("unchanged"), "old".to_owned()),
+            ]
+        );
+    }
+}
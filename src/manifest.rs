@@ -0,0 +1,244 @@
+//! Manifests: snapshots of a listing in CSV or JSON lines, that can be turned back into a
+//! `ListObjects` without listing S3 again.
+use super::*;
+use aws_sdk_s3::operation::list_objects_v2::ListObjectsV2Output;
+use aws_sdk_s3::primitives::{DateTime, DateTimeFormat};
+use aws_sdk_s3::types::{Object, ObjectStorageClass};
+use serde::{Deserialize, Serialize};
+use std::io::{BufRead, BufReader, Read};
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+
+/// Number of objects per page of a `ListObjects` read from a manifest, like in S3 listings.
+const PAGE_SIZE: usize = 1000;
+
+/// File format of a manifest.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ManifestFormat {
+    /// CSV with a header row.
+    Csv,
+    /// One JSON object per line.
+    JsonLines,
+}
+
+/// One object in a manifest.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ManifestEntry {
+    pub key: String,
+    pub size: i64,
+    pub etag: Option<String>,
+    /// RFC 3339, for example `2023-10-01T12:00:00Z`.
+    pub last_modified: Option<String>,
+    pub storage_class: Option<String>,
+}
+
+impl From<&Object> for ManifestEntry {
+    fn from(obj: &Object) -> Self {
+        Self {
+            key: obj.key.clone().unwrap_or_default(),
+            size: obj.size,
+            etag: obj.e_tag.clone(),
+            last_modified: obj
+                .last_modified
+                .and_then(|time| time.fmt(DateTimeFormat::DateTime).ok()),
+            storage_class: obj
+                .storage_class
+                .as_ref()
+                .map(|class| class.as_str().to_owned()),
+        }
+    }
+}
+
+impl ManifestEntry {
+    pub fn to_object(&self) -> Result<Object, Error> {
+        let last_modified = match &self.last_modified {
+            Some(date) => Some(
+                DateTime::from_str(date, DateTimeFormat::DateTime)
+                    .context(err::InvalidDate { date })?,
+            ),
+            None => None,
+        };
+        Ok(Object::builder()
+            .key(&self.key)
+            .size(self.size)
+            .set_e_tag(self.etag.clone())
+            .set_last_modified(last_modified)
+            .set_storage_class(self.storage_class.as_deref().map(ObjectStorageClass::from))
+            .build())
+    }
+}
+
+/// Parse the entries of a manifest.
+pub(crate) fn read_manifest<R>(
+    reader: R,
+    format: ManifestFormat,
+) -> Box<dyn Iterator<Item = Result<ManifestEntry, Error>> + Send>
+where
+    R: Read + Send + 'static,
+{
+    match format {
+        ManifestFormat::Csv => Box::new(
+            csv::Reader::from_reader(reader)
+                .into_deserialize()
+                .map(|entry| entry.map_err(Error::from)),
+        ),
+        ManifestFormat::JsonLines => Box::new(
+            BufReader::new(reader)
+                .lines()
+                .filter(|line| line.as_ref().map_or(true, |line| !line.trim().is_empty()))
+                .map(|line| {
+                    let line = line.with_context(|| err::Io {
+                        description: "reading manifest".to_owned(),
+                    })?;
+                    Ok(serde_json::from_str(&line)?)
+                }),
+        ),
+    }
+}
+
+/// Group manifest entries into pages, as if they were listed with ListObjectsV2.
+pub(crate) fn manifest_pages<I>(
+    entries: I,
+) -> impl Stream<Item = Result<ListObjectsV2Output, Error>> + Send
+where
+    I: Iterator<Item = Result<ManifestEntry, Error>> + Send,
+{
+    let mut entries = entries.map(|entry| entry.and_then(|entry| entry.to_object()));
+    stream::iter(std::iter::from_fn(move || {
+        let page = entries
+            .by_ref()
+            .take(PAGE_SIZE)
+            .collect::<Result<Vec<_>, _>>();
+        match page {
+            Ok(page) if page.is_empty() => None,
+            page => Some(page.map(|page| {
+                ListObjectsV2Output::builder()
+                    .key_count(page.len() as i32)
+                    .set_contents(Some(page))
+                    .build()
+            })),
+        }
+    }))
+}
+
+impl<S> ListObjects<S>
+where
+    S: Stream<Item = Result<ListObjectsV2Output, Error>> + Sized + Send + 'static,
+{
+    /// Write a manifest of all listed objects to `writer`, and return the writer.
+    ///
+    /// Read it back with `S3Algo::list_manifest`, for example to review a listing before running
+    /// `delete_all` on exactly the reviewed objects.
+    pub async fn write_manifest<W>(self, writer: W, format: ManifestFormat) -> Result<W, Error>
+    where
+        W: AsyncWrite + Unpin + Send,
+    {
+        let entries = self.flatten().map_ok(|obj| ManifestEntry::from(&obj));
+        write_entries(entries, writer, format).await
+    }
+}
+
+/// Write `entries` to `writer` in `format`, with a CSV header row of the field names of `T`, and
+/// return the writer.
+pub(crate) async fn write_entries<St, T, W>(
+    entries: St,
+    writer: W,
+    format: ManifestFormat,
+) -> Result<W, Error>
+where
+    St: Stream<Item = Result<T, Error>> + Send,
+    T: Serialize,
+    W: AsyncWrite + Unpin + Send,
+{
+    let (mut writer, _) = entries
+        .try_fold((writer, true), |(mut writer, first), entry| async move {
+            let line = match format {
+                ManifestFormat::Csv => {
+                    let mut csv = csv::WriterBuilder::new()
+                        .has_headers(first)
+                        .from_writer(vec![]);
+                    csv.serialize(&entry)?;
+                    csv.into_inner()
+                        .map_err(|e| e.into_error())
+                        .with_context(|| err::Io {
+                            description: "writing manifest".to_owned(),
+                        })?
+                }
+                ManifestFormat::JsonLines => {
+                    let mut line = serde_json::to_vec(&entry)?;
+                    line.push(b'\n');
+                    line
+                }
+            };
+            writer.write_all(&line).await.context(err::TokioIo)?;
+            Ok::<_, Error>((writer, false))
+        })
+        .await?;
+    writer.flush().await.context(err::TokioIo)?;
+    Ok(writer)
+}
+
+impl S3Algo {
+    /// A `ListObjects` of the objects in a manifest written by `ListObjects::write_manifest`,
+    /// without any requests to S3. The objects are taken to be in `bucket`.
+    ///
+    /// The manifest is read synchronously while the listing is consumed, so `reader` should be a
+    /// local file or a buffer.
+    pub fn list_manifest<R>(
+        &self,
+        bucket: String,
+        reader: R,
+        format: ManifestFormat,
+    ) -> ListObjects<impl Stream<Item = Result<ListObjectsV2Output, Error>> + Sized + Send>
+    where
+        R: Read + Send + 'static,
+    {
+        ListObjects {
+            s3: self.s3.clone(),
+            config: self.config.clone(),
+            bucket,
+            prefix: String::new(),
+            encryption: self.encryption.clone(),
+            stream: manifest_pages(read_manifest(reader, format)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn manifest_roundtrip() {
+        let algo = S3Algo::new(testing_sdk_client().await);
+        let objects = (0..2500)
+            .map(|i| {
+                Object::builder()
+                    .key(format!("dir/{}, \"quoted\"", i))
+                    .size(i)
+                    .e_tag(format!("\"{:x}\"", i))
+                    .last_modified(DateTime::from_secs(1_600_000_000 + i))
+                    .set_storage_class((i % 2 == 0).then_some(ObjectStorageClass::Glacier))
+                    .build()
+            })
+            .collect::<Vec<_>>();
+
+        for format in [ManifestFormat::Csv, ManifestFormat::JsonLines] {
+            let pages = objects.chunks(700).map(<[_]>::to_vec).collect();
+            let listing = ListObjects::from_pages("", pages).await;
+            let manifest = listing
+                .write_manifest(Vec::<u8>::new(), format)
+                .await
+                .unwrap();
+
+            let pages = algo
+                .list_manifest("test-bucket".into(), std::io::Cursor::new(manifest), format)
+                .stream
+                .map_ok(|page| page.contents.unwrap())
+                .try_collect::<Vec<_>>()
+                .await
+                .unwrap();
+            assert_eq!(pages.len(), 3);
+            assert_eq!(pages.concat(), objects);
+        }
+    }
+}
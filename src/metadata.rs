@@ -0,0 +1,174 @@
+//! Enriching listed objects with the metadata that only HeadObject (and GetObjectTagging) return.
+use super::*;
+use aws_sdk_s3::operation::head_object::HeadObjectOutput;
+use aws_sdk_s3::operation::list_objects_v2::ListObjectsV2Output;
+use aws_sdk_s3::types::{ChecksumMode, Object, Tag};
+use std::collections::HashMap;
+
+/// A listed object with its full metadata, yielded by `ListObjects::head_all`.
+#[derive(Clone, Debug)]
+pub struct ObjectMetadata {
+    /// The object as listed.
+    pub object: Object,
+    /// Response to a HeadObject request of the object, including checksums if the object has
+    /// them.
+    pub head: HeadObjectOutput,
+    /// The tags of the object, if requested.
+    pub tags: Option<Vec<Tag>>,
+}
+
+impl ObjectMetadata {
+    pub fn key(&self) -> &str {
+        self.object.key.as_deref().unwrap_or_default()
+    }
+    pub fn content_type(&self) -> Option<&str> {
+        self.head.content_type.as_deref()
+    }
+    /// User-defined metadata (`x-amz-meta-*`).
+    pub fn user_metadata(&self) -> Option<&HashMap<String, String>> {
+        self.head.metadata.as_ref()
+    }
+    /// The value of the tag `key`, if tags were requested and the object has it.
+    pub fn tag(&self, key: &str) -> Option<&str> {
+        self.tags
+            .as_ref()?
+            .iter()
+            .find(|tag| tag.key.as_deref() == Some(key))
+            .and_then(|tag| tag.value.as_deref())
+    }
+}
+
+impl<S> ListObjects<S>
+where
+    S: Stream<Item = Result<ListObjectsV2Output, Error>> + Sized + Send + 'static,
+{
+    /// Send a HeadObject request for every listed object, and also a GetObjectTagging request if
+    /// `with_tags` is true, to yield the objects with their full metadata.
+    ///
+    /// Up to `copy_parallelization` objects are requested at the same time, and the objects are
+    /// yielded in the order of the listing. Timeouts are estimated per object, like for deletes.
+    pub fn head_all(
+        self,
+        with_tags: bool,
+    ) -> impl Stream<Item = Result<ObjectMetadata, Error>> + Send {
+        let (s3, encryption, bucket) = (
+            self.s3.clone(),
+            self.encryption.clone(),
+            self.bucket.clone(),
+        );
+        let timeout = Arc::new(Mutex::new(TimeoutState::new(
+            self.config.algorithm.clone(),
+            self.config.delete_requests.clone(),
+        )));
+        let n_retries = self.config.algorithm.n_retries;
+        let parallelization = self.config.copy_parallelization;
+        self.flatten()
+            .map_ok(move |object| {
+                let (s3, encryption, bucket, timeout) = (
+                    s3.clone(),
+                    encryption.clone(),
+                    bucket.clone(),
+                    timeout.clone(),
+                );
+                async move {
+                    let key = object.key.clone().ok_or(Error::MissingKeyOrSize)?;
+                    let (report, (head, tags)) = s3_request(
+                        move || {
+                            let (s3, encryption, bucket, key) =
+                                (s3.clone(), encryption.clone(), bucket.clone(), key.clone());
+                            async move {
+                                Ok((
+                                    async move {
+                                        let head = encryption
+                                            .head_object(
+                                                s3.head_object()
+                                                    .bucket(&bucket)
+                                                    .key(&key)
+                                                    .checksum_mode(ChecksumMode::Enabled),
+                                            )
+                                            .send()
+                                            .await?;
+                                        let tags = if with_tags {
+                                            s3.get_object_tagging()
+                                                .bucket(bucket)
+                                                .key(key)
+                                                .send()
+                                                .await?
+                                                .tag_set
+                                        } else {
+                                            None
+                                        };
+                                        Ok::<_, Error>((head, tags))
+                                    },
+                                    1,
+                                ))
+                            }
+                        },
+                        |_, size| size,
+                        n_retries,
+                        timeout.clone(),
+                    )
+                    .await?;
+                    timeout.lock().await.update(&report);
+                    Ok::<_, Error>(ObjectMetadata {
+                        object,
+                        head,
+                        tags: if with_tags {
+                            Some(tags.unwrap_or_default())
+                        } else {
+                            None
+                        },
+                    })
+                }
+            })
+            .try_buffered(parallelization)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::rand_string;
+
+    #[tokio::test]
+    async fn test_s3_head_all() {
+        let algo = S3Algo::new(testing_sdk_client().await);
+        let dir = format!("{}/", rand_string(14));
+        for i in 0..3 {
+            algo.s3
+                .put_object()
+                .bucket("test-bucket")
+                .key(format!("{}{}", dir, i))
+                .body(vec![0u8; i].into())
+                .content_type("text/plain")
+                .metadata("index", i.to_string())
+                .tagging(format!("index={}", i))
+                .send()
+                .await
+                .unwrap();
+        }
+
+        let metadata = algo
+            .list_prefix("test-bucket".into(), Some(dir.clone()))
+            .head_all(true)
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap();
+        assert_eq!(metadata.len(), 3);
+        for (i, metadata) in metadata.iter().enumerate() {
+            assert_eq!(metadata.key(), format!("{}{}", dir, i));
+            assert_eq!(metadata.content_type(), Some("text/plain"));
+            assert_eq!(metadata.user_metadata().unwrap()["index"], i.to_string());
+            assert_eq!(metadata.tag("index"), Some(i.to_string().as_str()));
+            assert_eq!(metadata.head.content_length, i as i64);
+        }
+
+        let metadata = algo
+            .list_prefix("test-bucket".into(), Some(dir))
+            .head_all(false)
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap();
+        assert!(metadata.iter().all(|metadata| metadata.tags.is_none()));
+    }
+}
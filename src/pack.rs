@@ -0,0 +1,656 @@
+//! Packing of many small objects into tar shards, to avoid the per-request overhead of uploading
+//! them one by one. A JSON index maps each original key to its location in a shard, so that
+//! single objects can still be retrieved with ranged GETs through a `ShardReader`.
+use super::*;
+use crate::list_actions::download_object;
+use aws_sdk_s3::operation::put_object::builders::PutObjectFluentBuilder;
+use serde::{Deserialize, Serialize};
+use snafu::OptionExt;
+use std::collections::BTreeMap;
+
+/// Size of a tar block. Headers and (padded) contents are aligned to blocks.
+const TAR_BLOCK: usize = 512;
+
+/// Settings for `S3Algo::upload_files_packed`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PackConfig {
+    /// Objects smaller than this (in bytes) are packed into shards. Larger objects are uploaded
+    /// as they are.
+    pub threshold: usize,
+    /// Maximum size of a shard in bytes. A shard only exceeds it if a single packed object does
+    /// not fit in an empty shard, or because of the extra header blocks of keys longer than 100
+    /// bytes.
+    pub shard_size: usize,
+    /// Shards are uploaded with the key `{shard_prefix}{n:06}.tar`, counting `n` from 0.
+    pub shard_prefix: String,
+    /// Key of the JSON index object (see [`ShardIndex`](struct.ShardIndex.html)).
+    pub index_key: String,
+}
+
+impl PackConfig {
+    /// Pack objects below 1 MB into shards of up to 100 MB.
+    pub fn new(shard_prefix: String, index_key: String) -> Self {
+        Self {
+            threshold: 1_000_000,
+            shard_size: 100_000_000,
+            shard_prefix,
+            index_key,
+        }
+    }
+}
+
+/// Index of packed objects, uploaded as JSON to `PackConfig::index_key`.
+///
+/// The tar entries of the shards are named after the keys by `entry_path`, but the index always
+/// holds the original keys.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct ShardIndex {
+    /// Location of every packed object, by its original key.
+    pub entries: BTreeMap<String, ShardEntry>,
+}
+
+/// Location of a packed object.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ShardEntry {
+    /// Key of the shard that contains the object.
+    pub shard: String,
+    /// Byte offset of the object's contents in the shard.
+    pub offset: u64,
+    /// Size of the object's contents in bytes.
+    pub size: u64,
+}
+
+impl S3Algo {
+    /// Upload multiple files to S3 like `upload_files`, but pack all objects smaller than
+    /// `pack.threshold` into tar shards.
+    ///
+    /// Shards are built in memory, so at most `copy_parallelization + 1` shards are held at once.
+    /// When all shards and unpacked objects are uploaded, the index is uploaded to
+    /// `pack.index_key` and returned. `progress` is called for every uploaded object; shards,
+    /// unpacked objects and the index alike.
+    pub async fn upload_files_packed<P, F, I, R>(
+        &self,
+        bucket: String,
+        files: I,
+        pack: PackConfig,
+        progress: P,
+        default_request: R,
+    ) -> Result<ShardIndex, Error>
+    where
+        P: Fn(RequestReport) -> F + Clone + Send + Sync + 'static,
+        F: Future<Output = ()> + Send + 'static,
+        I: Iterator<Item = ObjectSource> + Send + 'static,
+        R: Fn(&Client) -> PutObjectFluentBuilder + Clone + Unpin + Sync + Send + 'static,
+    {
+        let index = Arc::new(std::sync::Mutex::new(ShardIndex::default()));
+        let index_key = pack.index_key.clone();
+        let packer = Packer {
+            files: files.fuse(),
+            config: pack,
+            shard: None,
+            n_shards: 0,
+            index: index.clone(),
+        };
+        let sources = stream::try_unfold(packer, |mut packer| async move {
+            Ok(packer.next_source().await?.map(|src| (src, packer)))
+        });
+        self.upload_stream(
+            bucket.clone(),
+            sources,
+            progress.clone(),
+            default_request.clone(),
+        )
+        .await?;
+
+        let index = std::mem::take(&mut *index.lock().unwrap());
+        let json = serde_json::to_vec(&index)?;
+        self.upload_files(
+            bucket,
+            std::iter::once(ObjectSource::data(json, index_key)),
+            progress,
+            default_request,
+        )
+        .await?;
+        Ok(index)
+    }
+}
+
+/// The path of the tar entry of `key`. Tar archives only hold relative paths without `..`, so
+/// leading slashes and empty or `.` segments are dropped, and `..` segments become `__`. A key
+/// without any other segment (such as `/`) becomes `_`.
+fn entry_path(key: &str) -> String {
+    let path = key
+        .split('/')
+        .filter(|segment| !segment.is_empty() && *segment != ".")
+        .map(|segment| if segment == ".." { "__" } else { segment })
+        .collect::<Vec<_>>()
+        .join("/");
+    if path.is_empty() {
+        "_".into()
+    } else {
+        path
+    }
+}
+
+/// Turns an iterator of object sources into the shards and unpacked objects to upload.
+struct Packer<I> {
+    files: std::iter::Fuse<I>,
+    config: PackConfig,
+    /// The shard that is currently being filled
+    shard: Option<tar::Builder<Vec<u8>>>,
+    /// Number of finished shards
+    n_shards: usize,
+    index: Arc<std::sync::Mutex<ShardIndex>>,
+}
+
+impl<I> Packer<I>
+where
+    I: Iterator<Item = ObjectSource>,
+{
+    /// Next object to upload: either a large object as it is, or a finished shard.
+    async fn next_source(&mut self) -> Result<Option<ObjectSource>, Error> {
+        while let Some(src) = self.files.next() {
+            if source_len(&src).await? >= self.config.threshold {
+                return Ok(Some(src));
+            }
+            let data = read_source(&src).await?;
+            if let Some(shard) = self.append(src.get_key(), &data)? {
+                return Ok(Some(shard));
+            }
+        }
+        self.finish_shard()
+    }
+
+    /// Add an object to the current shard. If the shard is full, it is first finished and
+    /// returned.
+    fn append(&mut self, key: &str, data: &[u8]) -> Result<Option<ObjectSource>, Error> {
+        let full = self.shard.as_ref().is_some_and(|tar| {
+            // Header, contents, and the two blocks that end the archive
+            tar.get_ref().len() + TAR_BLOCK + padded_len(data.len()) + 2 * TAR_BLOCK
+                > self.config.shard_size
+        });
+        let finished = if full { self.finish_shard()? } else { None };
+
+        let shard_key = self.shard_key();
+        let tar = self
+            .shard
+            .get_or_insert_with(|| tar::Builder::new(Vec::new()));
+        let mut header = tar::Header::new_gnu();
+        header.set_size(data.len() as u64);
+        header.set_mode(0o644);
+        tar.append_data(&mut header, entry_path(key), data)
+            .with_context(|| err::Io {
+                description: format!("packing {}", key),
+            })?;
+        // The contents are the last thing written, padded to a whole block
+        let offset = tar.get_ref().len() - padded_len(data.len());
+
+        self.index.lock().unwrap().entries.insert(
+            key.to_owned(),
+            ShardEntry {
+                shard: shard_key,
+                offset: offset as u64,
+                size: data.len() as u64,
+            },
+        );
+        Ok(finished)
+    }
+
+    fn finish_shard(&mut self) -> Result<Option<ObjectSource>, Error> {
+        match self.shard.take() {
+            Some(tar) => {
+                let key = self.shard_key();
+                let data = tar.into_inner().with_context({
+                    let key = key.clone();
+                    move || err::Io { description: key }
+                })?;
+                self.n_shards += 1;
+                Ok(Some(ObjectSource::data(data, key)))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn shard_key(&self) -> String {
+        format!("{}{:06}.tar", self.config.shard_prefix, self.n_shards)
+    }
+}
+
+/// Reads packed objects out of shards with ranged GETs. Created with `S3Algo::shard_reader`.
+#[derive(Clone)]
+pub struct ShardReader {
+    s3: Client,
+    config: Config,
+    encryption: Encryption,
+    bucket: String,
+    index: Arc<ShardIndex>,
+    /// Bulk reads coalesce two objects into one request if there are at most this many bytes
+    /// between them.
+    max_gap: u64,
+    timeout: Arc<Mutex<TimeoutState>>,
+}
+
+impl S3Algo {
+    /// Download the index at `index_key` (as uploaded by `upload_files_packed`) and create a
+    /// reader for the packed objects. The index is first requested with HeadObject, to base the
+    /// timeout of its download on its size.
+    pub async fn shard_reader(
+        &self,
+        bucket: String,
+        index_key: String,
+    ) -> Result<ShardReader, Error> {
+        let head = self
+            .encryption
+            .head_object(self.s3.head_object().bucket(&bucket).key(&index_key));
+        let (_, head) = s3_single_request(
+            move || {
+                let head = head.clone();
+                async move { Ok(head.send().await?) }
+            },
+            0.0,
+        )
+        .await?;
+        let mut reader = self.shard_reader_from_index(bucket.clone(), ShardIndex::default());
+        let (_, json) = download_object(
+            self.s3.clone(),
+            self.encryption.clone(),
+            bucket,
+            index_key,
+            head.content_length.max(0) as usize,
+            self.config.algorithm.n_retries,
+            reader.timeout.clone(),
+        )
+        .await?;
+        reader.index = Arc::new(serde_json::from_slice(&json)?);
+        Ok(reader)
+    }
+
+    /// Create a reader for the packed objects from an index that is already loaded.
+    pub fn shard_reader_from_index(&self, bucket: String, index: ShardIndex) -> ShardReader {
+        ShardReader {
+            s3: self.s3.clone(),
+            config: self.config.clone(),
+            encryption: self.encryption.clone(),
+            bucket,
+            index: Arc::new(index),
+            max_gap: 4 * TAR_BLOCK as u64,
+            timeout: Arc::new(Mutex::new(TimeoutState::new(
+                self.config.algorithm.clone(),
+                self.config.put_requests.clone(),
+            ))),
+        }
+    }
+}
+
+impl ShardReader {
+    pub fn index(&self) -> &ShardIndex {
+        &self.index
+    }
+
+    /// Set the largest number of bytes between two objects in a shard for which `get_many`
+    /// downloads both in one request (and throws away the bytes in between).
+    /// The default covers the tar header and padding between objects with keys of up to about
+    /// 1000 bytes.
+    pub fn with_max_gap(mut self, max_gap: u64) -> Self {
+        self.max_gap = max_gap;
+        self
+    }
+
+    /// Download a single packed object.
+    pub async fn get(&self, key: &str) -> Result<Vec<u8>, Error> {
+        let entry = self
+            .index
+            .entries
+            .get(key)
+            .context(err::MissingIndexEntry { key })?;
+        self.get_range(entry.shard.clone(), entry.offset, entry.offset + entry.size)
+            .await
+    }
+
+    /// Download many packed objects. Objects that lie close together in the same shard are
+    /// downloaded with one request (see `with_max_gap`). At most `copy_parallelization` requests
+    /// run at the same time. The objects arrive in no particular order.
+    pub fn get_many<I>(&self, keys: I) -> impl Stream<Item = Result<(String, Vec<u8>), Error>>
+    where
+        I: IntoIterator<Item = String>,
+    {
+        let reader = self.clone();
+        let ranges = plan_ranges(&self.index, keys, self.max_gap);
+        stream::iter(ranges)
+            .map(move |range| {
+                let reader = reader.clone();
+                async move {
+                    let ShardRange {
+                        shard,
+                        start,
+                        end,
+                        entries,
+                    } = range?;
+                    let data = reader.get_range(shard, start, end).await?;
+                    Ok::<_, Error>(
+                        entries
+                            .into_iter()
+                            .map(|(key, offset, size)| {
+                                let offset = (offset - start) as usize;
+                                (key, data[offset..offset + size as usize].to_vec())
+                            })
+                            .collect::<Vec<_>>(),
+                    )
+                }
+            })
+            .buffer_unordered(self.config.copy_parallelization)
+            .map_ok(|objects| stream::iter(objects).map(Ok))
+            .try_flatten()
+    }
+
+    /// Download the bytes `start..end` of a shard.
+    fn get_range(
+        &self,
+        shard: String,
+        start: u64,
+        end: u64,
+    ) -> impl Future<Output = Result<Vec<u8>, Error>> + Send + 'static {
+        let (s3, bucket, encryption, timeout) = (
+            self.s3.clone(),
+            self.bucket.clone(),
+            self.encryption.clone(),
+            self.timeout.clone(),
+        );
+        let n_retries = self.config.algorithm.n_retries;
+        async move {
+            if end == start {
+                // An empty range can not be expressed in a Range header
+                return Ok(vec![]);
+            }
+            let (report, data) = s3_request(
+                move || {
+                    let (s3, bucket, shard, encryption) = (
+                        s3.clone(),
+                        bucket.clone(),
+                        shard.clone(),
+                        encryption.clone(),
+                    );
+                    async move {
+                        Ok((
+                            async move {
+                                let request = s3
+                                    .get_object()
+                                    .bucket(bucket.clone())
+                                    .key(shard.clone())
+                                    .range(format!("bytes={}-{}", start, end - 1));
+                                let output = encryption
+                                    .get_object(request)
+                                    .send()
+                                    .await
+                                    .context(err::GetObject { key: shard, bucket })?;
+                                Ok(output.body.collect().await?.into_bytes().to_vec())
+                            },
+                            (end - start) as usize,
+                        ))
+                    }
+                },
+                |_, size| size,
+                n_retries,
+                timeout.clone(),
+            )
+            .await?;
+            timeout.lock().await.update(&report);
+            Ok(data)
+        }
+    }
+}
+
+/// A byte range of a shard that covers one or more packed objects.
+#[derive(Debug, PartialEq)]
+struct ShardRange {
+    shard: String,
+    start: u64,
+    /// Exclusive
+    end: u64,
+    /// Key, offset and size of the objects in the range
+    entries: Vec<(String, u64, u64)>,
+}
+
+/// Group the requested objects into as few ranges as possible, merging objects of the same shard
+/// that are at most `max_gap` bytes apart. Keys that are not in the index give an error each.
+fn plan_ranges<I>(index: &ShardIndex, keys: I, max_gap: u64) -> Vec<Result<ShardRange, Error>>
+where
+    I: IntoIterator<Item = String>,
+{
+    let mut plan = vec![];
+    let mut by_shard: BTreeMap<&str, Vec<(String, &ShardEntry)>> = BTreeMap::new();
+    for key in keys {
+        match index.entries.get(&key) {
+            Some(entry) => by_shard
+                .entry(entry.shard.as_str())
+                .or_default()
+                .push((key, entry)),
+            None => plan.push(Err(Error::MissingIndexEntry { key })),
+        }
+    }
+    for (shard, mut entries) in by_shard {
+        entries.sort_by_key(|(_, entry)| entry.offset);
+        let mut current: Option<ShardRange> = None;
+        for (key, entry) in entries {
+            let end = entry.offset + entry.size;
+            let extend = matches!(&current, Some(range) if entry.offset <= range.end + max_gap);
+            if extend {
+                let range = current.as_mut().unwrap();
+                range.end = range.end.max(end);
+                range.entries.push((key, entry.offset, entry.size));
+            } else {
+                plan.extend(current.take().map(Ok));
+                current = Some(ShardRange {
+                    shard: shard.to_owned(),
+                    start: entry.offset,
+                    end,
+                    entries: vec![(key, entry.offset, entry.size)],
+                });
+            }
+        }
+        plan.extend(current.map(Ok));
+    }
+    plan
+}
+
+fn padded_len(len: usize) -> usize {
+    len.div_ceil(TAR_BLOCK) * TAR_BLOCK
+}
+
+async fn source_len(src: &ObjectSource) -> Result<usize, Error> {
+    match src {
+        ObjectSource::File { path, .. } => tokio::fs::metadata(path)
+            .await
+            .map(|metadata| metadata.len() as usize)
+            .with_context({
+                let path = path.clone();
+                move || err::Io {
+                    description: path.display().to_string(),
+                }
+            }),
+        ObjectSource::Data { data, .. } => Ok(data.len()),
+        ObjectSource::FileRange { len, .. } | ObjectSource::Streamed { len, .. } => {
+            Ok(*len as usize)
+        }
+    }
+}
+
+async fn read_source(src: &ObjectSource) -> Result<Vec<u8>, Error> {
+    match src {
+        ObjectSource::File { path, .. } => tokio::fs::read(path).await.with_context({
+            let path = path.clone();
+            move || err::Io {
+                description: path.display().to_string(),
+            }
+        }),
+        ObjectSource::Data { data, .. } => Ok(data.clone()),
+        ObjectSource::FileRange { .. } | ObjectSource::Streamed { .. } => {
+            let (stream, _) = src.create_stream().await?;
+            Ok(stream.collect().await?.into_bytes().to_vec())
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Read;
+
+    #[tokio::test]
+    async fn pack_into_shards() {
+        let files = (0..10).map(|i| ObjectSource::data(vec![i as u8; 100 * i], format!("f{}", i)));
+        let index = Arc::new(std::sync::Mutex::new(ShardIndex::default()));
+        let mut packer = Packer {
+            files: files.fuse(),
+            config: PackConfig {
+                threshold: 800,
+                shard_size: 4096,
+                shard_prefix: "shards/".into(),
+                index_key: "index.json".into(),
+            },
+            shard: None,
+            n_shards: 0,
+            index: index.clone(),
+        };
+        let mut shards = BTreeMap::new();
+        let mut unpacked = vec![];
+        while let Some(src) = packer.next_source().await.unwrap() {
+            match src {
+                ObjectSource::Data { data, key } if key.starts_with("shards/") => {
+                    assert!(data.len() <= 4096);
+                    shards.insert(key, data);
+                }
+                other => unpacked.push(other.get_key().to_owned()),
+            }
+        }
+        assert_eq!(unpacked, vec!["f8", "f9"]);
+
+        // Every packed object can be read back from its shard, both with the index and with tar
+        let index = index.lock().unwrap();
+        assert_eq!(index.entries.len(), 8);
+        for (key, entry) in &index.entries {
+            let i: usize = key[1..].parse().unwrap();
+            let shard = &shards[&entry.shard];
+            let start = entry.offset as usize;
+            assert_eq!(
+                &shard[start..start + entry.size as usize],
+                &vec![i as u8; 100 * i][..]
+            );
+        }
+        for shard in shards.values() {
+            let mut archive = tar::Archive::new(&shard[..]);
+            for entry in archive.entries().unwrap() {
+                let mut entry = entry.unwrap();
+                let key = entry.path().unwrap().to_string_lossy().to_string();
+                let mut contents = vec![];
+                entry.read_to_end(&mut contents).unwrap();
+                assert_eq!(index.entries[&key].size as usize, contents.len());
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn pack_unusual_keys() {
+        let keys = vec!["/abs", "a/../b", "..", "/", "a//./c"];
+        let files = keys
+            .clone()
+            .into_iter()
+            .map(|key| ObjectSource::data(key.as_bytes().to_vec(), key.to_owned()));
+        let index = Arc::new(std::sync::Mutex::new(ShardIndex::default()));
+        let mut packer = Packer {
+            files: files.fuse(),
+            config: PackConfig::new("shards/".into(), "index.json".into()),
+            shard: None,
+            n_shards: 0,
+            index: index.clone(),
+        };
+        let shard = match packer.next_source().await.unwrap() {
+            Some(ObjectSource::Data { data, .. }) => data,
+            other => panic!("expected a shard, got {:?}", other),
+        };
+        assert!(packer.next_source().await.unwrap().is_none());
+
+        // The index keeps the keys, while the entries have relative paths
+        let index = index.lock().unwrap();
+        for key in &keys {
+            let entry = &index.entries[*key];
+            let start = entry.offset as usize;
+            assert_eq!(&shard[start..start + entry.size as usize], key.as_bytes());
+        }
+        let mut archive = tar::Archive::new(&shard[..]);
+        let paths = archive
+            .entries()
+            .unwrap()
+            .map(|entry| entry.unwrap().path().unwrap().to_string_lossy().to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(paths, vec!["abs", "a/__/b", "__", "_", "a/c"]);
+    }
+
+    #[test]
+    fn coalesce_ranges() {
+        let entry = |shard: &str, offset, size| ShardEntry {
+            shard: shard.into(),
+            offset,
+            size,
+        };
+        let mut index = ShardIndex::default();
+        index.entries.insert("a".into(), entry("s0", 512, 10));
+        index.entries.insert("b".into(), entry("s0", 1536, 600));
+        index.entries.insert("c".into(), entry("s0", 10_000, 5));
+        index.entries.insert("d".into(), entry("s1", 512, 0));
+
+        let keys = vec!["c", "a", "b", "d", "x"].into_iter().map(String::from);
+        let plan = plan_ranges(&index, keys, 1024);
+        assert!(matches!(&plan[0], Err(Error::MissingIndexEntry { key }) if key == "x"));
+        let ranges = plan[1..]
+            .iter()
+            .map(|range| range.as_ref().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(ranges.len(), 3);
+        assert_eq!((ranges[0].start, ranges[0].end), (512, 2136));
+        assert_eq!(ranges[0].entries.len(), 2);
+        assert_eq!((ranges[1].start, ranges[1].end), (10_000, 10_005));
+        assert_eq!(ranges[2].shard, "s1");
+    }
+
+    #[tokio::test]
+    async fn test_s3_packed_roundtrip() {
+        let algo = S3Algo::new(testing_sdk_client().await);
+        let dir = crate::test::rand_string(14);
+        let files = (0..100).map({
+            let dir = dir.clone();
+            move |i| ObjectSource::data(format!("contents of {}", i), format!("{}/{}", dir, i))
+        });
+        let pack = PackConfig {
+            shard_size: 10_000,
+            ..PackConfig::new(format!("{}/shards/", dir), format!("{}/index.json", dir))
+        };
+        let index = algo
+            .upload_files_packed(
+                "test-bucket".into(),
+                files,
+                pack,
+                |_| async {},
+                |client| client.put_object(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(index.entries.len(), 100);
+
+        let reader = algo
+            .shard_reader("test-bucket".into(), format!("{}/index.json", dir))
+            .await
+            .unwrap();
+        assert_eq!(reader.index(), &index);
+        let contents = reader.get(&format!("{}/42", dir)).await.unwrap();
+        assert_eq!(contents, b"contents of 42");
+
+        let keys = (0..100).step_by(3).map(|i| format!("{}/{}", dir, i));
+        let objects = reader.get_many(keys).try_collect::<Vec<_>>().await.unwrap();
+        assert_eq!(objects.len(), 34);
+        for (key, contents) in objects {
+            let i = key.rsplit('/').next().unwrap();
+            assert_eq!(contents, format!("contents of {}", i).into_bytes());
+        }
+    }
+}
@@ -0,0 +1,241 @@
+//! Presigned URLs, to give others temporary access to objects without sharing credentials.
+use super::*;
+use aws_sdk_s3::operation::list_objects_v2::ListObjectsV2Output;
+use aws_sdk_s3::presigning::PresigningConfig;
+use aws_sdk_s3::primitives::{DateTime, DateTimeFormat};
+
+/// What a presigned URL allows.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PresignMethod {
+    /// Download the object with GET.
+    Get,
+    /// Upload the object with PUT, replacing it if it exists.
+    Put,
+}
+
+/// A presigned URL of one object.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde1", derive(serde::Serialize, serde::Deserialize))]
+pub struct PresignedUrl {
+    pub key: String,
+    pub url: String,
+    /// When the URL expires, in RFC 3339, for example `2023-10-01T12:00:00Z`.
+    pub expires: String,
+}
+
+/// Presign URLs for `keys` of `bucket`. Presigning is done locally, without requests to S3.
+fn presign_stream<St>(
+    s3: Client,
+    bucket: String,
+    keys: St,
+    method: PresignMethod,
+    expires_in: Duration,
+) -> impl Stream<Item = Result<PresignedUrl, Error>> + Send
+where
+    St: Stream<Item = Result<String, Error>> + Send + 'static,
+{
+    // The same start time for all URLs, so that they expire at the same time
+    let config = PresigningConfig::expires_in(expires_in).context(err::Presigning);
+    let expires = config.as_ref().ok().and_then(|config| {
+        DateTime::from(config.start_time() + config.expires())
+            .fmt(DateTimeFormat::DateTime)
+            .ok()
+    });
+    let config = match config {
+        Ok(config) => config,
+        Err(e) => return stream::once(future::err(e)).left_stream(),
+    };
+    let expires = expires.unwrap_or_default();
+    keys.and_then(move |key| {
+        let (s3, bucket, config, expires) =
+            (s3.clone(), bucket.clone(), config.clone(), expires.clone());
+        async move {
+            let request = match method {
+                PresignMethod::Get => {
+                    s3.get_object()
+                        .bucket(bucket)
+                        .key(&key)
+                        .presigned(config)
+                        .await?
+                }
+                PresignMethod::Put => {
+                    s3.put_object()
+                        .bucket(bucket)
+                        .key(&key)
+                        .presigned(config)
+                        .await?
+                }
+            };
+            Ok::<_, Error>(PresignedUrl {
+                key,
+                url: request.uri().to_string(),
+                expires,
+            })
+        }
+    })
+    .right_stream()
+}
+
+impl<S> ListObjects<S>
+where
+    S: Stream<Item = Result<ListObjectsV2Output, Error>> + Sized + Send + 'static,
+{
+    /// Presign a URL for every listed object, valid for `expires_in` (at most one week, and no
+    /// longer than the credentials of the client).
+    ///
+    /// The encryption settings are not applied: a URL of an object with SSE-C only works if the
+    /// key is sent along as headers.
+    pub fn presign_all(
+        self,
+        method: PresignMethod,
+        expires_in: Duration,
+    ) -> impl Stream<Item = Result<PresignedUrl, Error>> + Send {
+        let (s3, bucket) = (self.s3.clone(), self.bucket.clone());
+        let keys = self
+            .flatten()
+            .and_then(|obj| future::ready(obj.key.ok_or(Error::MissingKeyOrSize)));
+        presign_stream(s3, bucket, keys, method, expires_in)
+    }
+}
+
+impl S3Algo {
+    /// Like `ListObjects::presign_all`, but for the given `keys` of `bucket`, which do not need to
+    /// exist. For example to let others upload to chosen keys with `PresignMethod::Put`.
+    pub fn presign_keys<I>(
+        &self,
+        bucket: String,
+        keys: I,
+        method: PresignMethod,
+        expires_in: Duration,
+    ) -> impl Stream<Item = Result<PresignedUrl, Error>> + Send
+    where
+        I: IntoIterator<Item = String>,
+        I::IntoIter: Send + 'static,
+    {
+        presign_stream(
+            self.s3.clone(),
+            bucket,
+            stream::iter(keys).map(Ok),
+            method,
+            expires_in,
+        )
+    }
+}
+
+/// Write presigned URLs to `writer` as a manifest in `format`, with the columns `key`, `url` and
+/// `expires`, and return the writer.
+#[cfg(feature = "serde1")]
+pub async fn write_presigned_urls<St, W>(
+    urls: St,
+    writer: W,
+    format: ManifestFormat,
+) -> Result<W, Error>
+where
+    St: Stream<Item = Result<PresignedUrl, Error>> + Send,
+    W: tokio::io::AsyncWrite + Unpin + Send,
+{
+    crate::manifest::write_entries(urls, writer, format).await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::rand_string;
+
+    #[tokio::test]
+    async fn presign_too_long() {
+        let algo = S3Algo::new(testing_sdk_client().await);
+        let result = algo
+            .presign_keys(
+                "test-bucket".into(),
+                vec!["a".to_owned()],
+                PresignMethod::Get,
+                Duration::from_secs(8 * 24 * 3600),
+            )
+            .try_collect::<Vec<_>>()
+            .await;
+        assert!(matches!(result, Err(Error::Presigning { .. })));
+    }
+
+    #[tokio::test]
+    async fn test_s3_presign_all() {
+        let algo = S3Algo::new(testing_sdk_client().await);
+        let dir = format!("{}/", rand_string(14));
+        for i in 0..3 {
+            algo.s3
+                .put_object()
+                .bucket("test-bucket")
+                .key(format!("{}{}", dir, i))
+                .body(vec![i as u8; 3].into())
+                .send()
+                .await
+                .unwrap();
+        }
+
+        let urls = algo
+            .list_prefix("test-bucket".into(), Some(dir.clone()))
+            .presign_all(PresignMethod::Get, Duration::from_secs(600))
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap();
+        assert_eq!(urls.len(), 3);
+        for (i, url) in urls.iter().enumerate() {
+            assert_eq!(url.key, format!("{}{}", dir, i));
+            assert!(url.url.contains("X-Amz-Signature="));
+            assert!(url.url.contains("X-Amz-Expires=600"));
+            assert_eq!(url.expires, urls[0].expires);
+        }
+
+        let key = format!("{}new", dir);
+        let urls = algo
+            .presign_keys(
+                "test-bucket".into(),
+                vec![key.clone()],
+                PresignMethod::Put,
+                Duration::from_secs(600),
+            )
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap();
+        assert_eq!(urls[0].key, key);
+        assert!(urls[0].url.contains(&key));
+    }
+
+    #[cfg(feature = "serde1")]
+    #[tokio::test]
+    async fn presigned_manifest() {
+        let urls = vec![
+            PresignedUrl {
+                key: "a b".into(),
+                url: "http://host/a%20b?X-Amz-Signature=1".into(),
+                expires: "2023-10-01T12:00:00Z".into(),
+            },
+            PresignedUrl {
+                key: "c".into(),
+                url: "http://host/c?X-Amz-Signature=2".into(),
+                expires: "2023-10-01T12:00:00Z".into(),
+            },
+        ];
+        let csv = write_presigned_urls(
+            stream::iter(urls.clone()).map(Ok),
+            Vec::<u8>::new(),
+            ManifestFormat::Csv,
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            String::from_utf8(csv).unwrap(),
+            "key,url,expires\n\
+             a b,http://host/a%20b?X-Amz-Signature=1,2023-10-01T12:00:00Z\n\
+             c,http://host/c?X-Amz-Signature=2,2023-10-01T12:00:00Z\n"
+        );
+        let json = write_presigned_urls(
+            stream::iter(urls).map(Ok),
+            Vec::<u8>::new(),
+            ManifestFormat::JsonLines,
+        )
+        .await
+        .unwrap();
+        assert_eq!(String::from_utf8(json).unwrap().lines().count(), 2);
+    }
+}
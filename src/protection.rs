@@ -0,0 +1,283 @@
+//! Access control and Object Lock settings of all listed objects: canned ACLs, retention and
+//! legal holds.
+use super::*;
+use aws_sdk_s3::operation::list_objects_v2::ListObjectsV2Output;
+use aws_sdk_s3::primitives::DateTime;
+use aws_sdk_s3::types::{
+    ObjectCannedAcl, ObjectLockLegalHold, ObjectLockLegalHoldStatus, ObjectLockRetention,
+    ObjectLockRetentionMode,
+};
+
+/// An object that a bulk operation failed on, after all retries.
+#[derive(Debug)]
+pub struct KeyFailure {
+    pub key: String,
+    pub error: Error,
+}
+
+impl<S> ListObjects<S>
+where
+    S: Stream<Item = Result<ListObjectsV2Output, Error>> + Sized + Send + 'static,
+{
+    /// Send the request made by `request(s3, bucket, key)` for every listed object, with up to
+    /// `copy_parallelization` requests at the same time. A failed object does not stop the
+    /// others; it is returned as a `KeyFailure`. Only an error in the listing itself aborts,
+    /// including a listed object without key (`Error::MissingKeyOrSize`).
+    fn for_each_key<R, Fut, P, F>(
+        self,
+        request: R,
+        progress: P,
+    ) -> impl Future<Output = Result<Vec<KeyFailure>, Error>>
+    where
+        R: Fn(Client, String, String) -> Fut + Clone + Unpin + Send + Sync + 'static,
+        Fut: Future<Output = Result<(), Error>> + Send + 'static,
+        P: Fn(RequestReport) -> F + Clone + Send + Sync + 'static,
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let (s3, bucket) = (self.s3.clone(), self.bucket.clone());
+        let timeout = Arc::new(Mutex::new(TimeoutState::new(
+            self.config.algorithm.clone(),
+            self.config.delete_requests.clone(),
+        )));
+        let n_retries = self.config.algorithm.n_retries;
+        let parallelization = self.config.copy_parallelization;
+        self.flatten()
+            .map_ok(move |object| {
+                let (s3, bucket, request, timeout, progress) = (
+                    s3.clone(),
+                    bucket.clone(),
+                    request.clone(),
+                    timeout.clone(),
+                    progress.clone(),
+                );
+                async move {
+                    let key = object.key.ok_or(Error::MissingKeyOrSize)?;
+                    let key2 = key.clone();
+                    let result = s3_request(
+                        move || {
+                            let (s3, bucket, key, request) =
+                                (s3.clone(), bucket.clone(), key2.clone(), request.clone());
+                            async move { Ok((request(s3, bucket, key), 1)) }
+                        },
+                        |_, size| size,
+                        n_retries,
+                        timeout.clone(),
+                    )
+                    .await;
+                    match result {
+                        Ok((report, _)) => {
+                            timeout.lock().await.update(&report);
+                            progress(report).await;
+                            Ok::<_, Error>(None)
+                        }
+                        Err(error) => Ok(Some(KeyFailure { key, error })),
+                    }
+                }
+            })
+            .try_buffer_unordered(parallelization)
+            .try_filter_map(future::ok)
+            .try_collect()
+    }
+
+    /// Apply the canned ACL `acl` to all listed objects.
+    ///
+    /// `progress` is called with the `RequestReport` of each object, where `size` is 1. Returns
+    /// the objects that failed after all retries.
+    pub fn set_acl<P, F>(
+        self,
+        acl: ObjectCannedAcl,
+        progress: P,
+    ) -> impl Future<Output = Result<Vec<KeyFailure>, Error>>
+    where
+        P: Fn(RequestReport) -> F + Clone + Send + Sync + 'static,
+        F: Future<Output = ()> + Send + 'static,
+    {
+        self.for_each_key(
+            move |s3: Client, bucket, key| {
+                let acl = acl.clone();
+                async move {
+                    s3.put_object_acl()
+                        .bucket(bucket)
+                        .key(key)
+                        .acl(acl)
+                        .send()
+                        .await?;
+                    Ok::<_, Error>(())
+                }
+            },
+            progress,
+        )
+    }
+
+    /// Set the Object Lock retention of all listed objects to `mode` until `retain_until`. The
+    /// bucket must have Object Lock enabled.
+    ///
+    /// An existing retention can be extended, but only shortened or removed in `GOVERNANCE` mode,
+    /// with permission to bypass it - which this function does not request.
+    ///
+    /// `progress` is called with the `RequestReport` of each object, where `size` is 1. Returns
+    /// the objects that failed after all retries.
+    pub fn set_retention<T, P, F>(
+        self,
+        mode: ObjectLockRetentionMode,
+        retain_until: T,
+        progress: P,
+    ) -> impl Future<Output = Result<Vec<KeyFailure>, Error>>
+    where
+        T: Into<DateTime>,
+        P: Fn(RequestReport) -> F + Clone + Send + Sync + 'static,
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let retention = ObjectLockRetention::builder()
+            .mode(mode)
+            .retain_until_date(retain_until.into())
+            .build();
+        self.for_each_key(
+            move |s3: Client, bucket, key| {
+                let retention = retention.clone();
+                async move {
+                    s3.put_object_retention()
+                        .bucket(bucket)
+                        .key(key)
+                        .retention(retention)
+                        .send()
+                        .await?;
+                    Ok::<_, Error>(())
+                }
+            },
+            progress,
+        )
+    }
+
+    /// Turn the legal hold of all listed objects on or off. The bucket must have Object Lock
+    /// enabled.
+    ///
+    /// `progress` is called with the `RequestReport` of each object, where `size` is 1. Returns
+    /// the objects that failed after all retries.
+    pub fn set_legal_hold<P, F>(
+        self,
+        on: bool,
+        progress: P,
+    ) -> impl Future<Output = Result<Vec<KeyFailure>, Error>>
+    where
+        P: Fn(RequestReport) -> F + Clone + Send + Sync + 'static,
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let status = if on {
+            ObjectLockLegalHoldStatus::On
+        } else {
+            ObjectLockLegalHoldStatus::Off
+        };
+        let legal_hold = ObjectLockLegalHold::builder().status(status).build();
+        self.for_each_key(
+            move |s3: Client, bucket, key| {
+                let legal_hold = legal_hold.clone();
+                async move {
+                    s3.put_object_legal_hold()
+                        .bucket(bucket)
+                        .key(key)
+                        .legal_hold(legal_hold)
+                        .send()
+                        .await?;
+                    Ok::<_, Error>(())
+                }
+            },
+            progress,
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::{locked_test_bucket, rand_string};
+    use std::time::SystemTime;
+
+    async fn put_objects(s3: &Client, bucket: &str, dir: &str, n: usize) {
+        for i in 0..n {
+            s3.put_object()
+                .bucket(bucket)
+                .key(format!("{}{}", dir, i))
+                .body(vec![0u8; 3].into())
+                .send()
+                .await
+                .unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_s3_object_lock() {
+        let algo = S3Algo::new(testing_sdk_client().await);
+        let bucket = locked_test_bucket(&algo.s3).await;
+        let dir = format!("{}/", rand_string(14));
+        put_objects(&algo.s3, &bucket, &dir, 3).await;
+        let listing = || algo.list_prefix(bucket.clone(), Some(dir.clone()));
+
+        let failures = listing().set_legal_hold(true, |_| async {}).await.unwrap();
+        assert!(failures.is_empty());
+        let retain_until = SystemTime::now() + Duration::from_secs(3600);
+        let failures = listing()
+            .set_retention(
+                ObjectLockRetentionMode::Governance,
+                retain_until,
+                |_| async {},
+            )
+            .await
+            .unwrap();
+        assert!(failures.is_empty());
+
+        for i in 0..3 {
+            let key = format!("{}{}", dir, i);
+            let legal_hold = algo
+                .s3
+                .get_object_legal_hold()
+                .bucket(&bucket)
+                .key(&key)
+                .send()
+                .await
+                .unwrap()
+                .legal_hold
+                .unwrap();
+            assert_eq!(legal_hold.status, Some(ObjectLockLegalHoldStatus::On));
+            let retention = algo
+                .s3
+                .get_object_retention()
+                .bucket(&bucket)
+                .key(&key)
+                .send()
+                .await
+                .unwrap()
+                .retention
+                .unwrap();
+            assert_eq!(retention.mode, Some(ObjectLockRetentionMode::Governance));
+        }
+
+        let failures = listing().set_legal_hold(false, |_| async {}).await.unwrap();
+        assert!(failures.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_s3_acl_and_failures() {
+        let mut algo = S3Algo::new(testing_sdk_client().await);
+        algo.config.algorithm.n_retries = 1;
+        let dir = format!("{}/", rand_string(14));
+        put_objects(&algo.s3, "test-bucket", &dir, 2).await;
+
+        let failures = algo
+            .list_prefix("test-bucket".into(), Some(dir.clone()))
+            .set_acl(ObjectCannedAcl::Private, |_| async {})
+            .await
+            .unwrap();
+        assert!(failures.is_empty());
+
+        // Legal holds need Object Lock, which `test-bucket` does not have
+        let mut failures = algo
+            .list_prefix("test-bucket".into(), Some(dir.clone()))
+            .set_legal_hold(true, |_| async {})
+            .await
+            .unwrap();
+        failures.sort_by(|a, b| a.key.cmp(&b.key));
+        let keys = failures.iter().map(|f| f.key.clone()).collect::<Vec<_>>();
+        assert_eq!(keys, vec![format!("{}0", dir), format!("{}1", dir)]);
+    }
+}
@@ -0,0 +1,237 @@
+//! Copying listed objects with new metadata, chosen per object.
+use super::*;
+use crate::copy::{copy_objects, tagging_header};
+use aws_sdk_s3::operation::head_object::HeadObjectOutput;
+use aws_sdk_s3::operation::list_objects_v2::ListObjectsV2Output;
+use aws_sdk_s3::primitives::DateTime;
+use aws_sdk_s3::types::{MetadataDirective, Object, StorageClass, TaggingDirective};
+use std::collections::{BTreeMap, HashMap};
+
+/// The destination and the new metadata of an object copied by `ListObjects::copy_rewrite`.
+///
+/// All metadata is replaced, and so is the storage class: fields that are `None` are not set on
+/// the copy, even if the source has them. Start from `Rewrite::from_head` to only change some of them.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Rewrite {
+    /// Key of the copy.
+    pub key: String,
+    /// User-defined metadata (`x-amz-meta-*`).
+    pub metadata: HashMap<String, String>,
+    pub content_type: Option<String>,
+    pub content_encoding: Option<String>,
+    pub content_disposition: Option<String>,
+    pub content_language: Option<String>,
+    pub cache_control: Option<String>,
+    pub expires: Option<DateTime>,
+    /// Storage class of the copy. `None` is `STANDARD`, since a copy does not keep the storage
+    /// class of the source.
+    pub storage_class: Option<StorageClass>,
+    pub website_redirect_location: Option<String>,
+    /// Tags of the copy. `None` copies the tags of the source.
+    pub tags: Option<BTreeMap<String, String>>,
+}
+
+impl Rewrite {
+    /// A rewrite to `key` that keeps the metadata of the source, as returned by HeadObject.
+    pub fn from_head(key: String, head: &HeadObjectOutput) -> Self {
+        Self {
+            key,
+            metadata: head.metadata.clone().unwrap_or_default(),
+            content_type: head.content_type.clone(),
+            content_encoding: head.content_encoding.clone(),
+            content_disposition: head.content_disposition.clone(),
+            content_language: head.content_language.clone(),
+            cache_control: head.cache_control.clone(),
+            expires: head.expires,
+            storage_class: head.storage_class.clone(),
+            website_redirect_location: head.website_redirect_location.clone(),
+            tags: None,
+        }
+    }
+}
+
+impl<S> ListObjects<S>
+where
+    S: Stream<Item = Result<ListObjectsV2Output, Error>> + Sized + Send + 'static,
+{
+    /// Copy listed objects to `dest_bucket` (or the same bucket) with new metadata, applied with
+    /// `MetadataDirective::Replace`.
+    ///
+    /// `rewrite` is called with each listed object, and with its HeadObject response if `with_head`
+    /// is true, and returns the key and metadata of the copy, or `None` to skip the object. The
+    /// key may be that of the source, to fix the metadata of objects in place.
+    ///
+    /// Objects larger than 5 GiB are copied in parts, and each copy or part is retried on its own.
+    /// `progress` is called with the `RequestReport` of each copied object, where `size` is
+    /// the size of the object in bytes.
+    pub fn copy_rewrite<R, P, F>(
+        self,
+        dest_bucket: Option<String>,
+        with_head: bool,
+        rewrite: R,
+        progress: P,
+    ) -> impl Future<Output = Result<(), Error>>
+    where
+        R: Fn(&Object, Option<&HeadObjectOutput>) -> Option<Rewrite> + Send + Sync + 'static,
+        P: Fn(RequestReport) -> F + Clone + Send + Sync + 'static,
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let (s3, config, encryption, bucket) = (
+            self.s3.clone(),
+            self.config.clone(),
+            self.encryption.clone(),
+            self.bucket.clone(),
+        );
+        let dest_bucket = dest_bucket.unwrap_or_else(|| bucket.clone());
+        let s3_2 = s3.clone();
+        let request = move |obj: Object, head: Option<HeadObjectOutput>| {
+            let rewrite = rewrite(&obj, head.as_ref())?;
+            let tagging = rewrite.tags.as_ref().map(|tags| {
+                tagging_header(
+                    tags.iter()
+                        .map(|(key, value)| (key.as_str(), value.as_str())),
+                )
+            });
+            let request = s3_2
+                .copy_object()
+                .bucket(dest_bucket.clone())
+                .key(rewrite.key)
+                .metadata_directive(MetadataDirective::Replace)
+                .set_metadata(Some(rewrite.metadata))
+                .set_content_type(rewrite.content_type)
+                .set_content_encoding(rewrite.content_encoding)
+                .set_content_disposition(rewrite.content_disposition)
+                .set_content_language(rewrite.content_language)
+                .set_cache_control(rewrite.cache_control)
+                .set_expires(rewrite.expires)
+                .set_storage_class(rewrite.storage_class)
+                .set_website_redirect_location(rewrite.website_redirect_location);
+            let request = match tagging {
+                Some(tagging) => request
+                    .tagging_directive(TaggingDirective::Replace)
+                    .tagging(tagging),
+                None => request,
+            };
+            Some((obj, request))
+        };
+        let requests = if with_head {
+            self.head_all(false)
+                .try_filter_map(move |metadata| {
+                    future::ok(request(metadata.object, Some(metadata.head)))
+                })
+                .boxed()
+        } else {
+            self.flatten()
+                .try_filter_map(move |obj| future::ok(request(obj, None)))
+                .boxed()
+        };
+        copy_objects(s3, &config, encryption, bucket, requests)
+            .try_for_each(move |(_, report)| progress(report).map(Ok))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::rand_string;
+
+    #[tokio::test]
+    async fn test_s3_copy_rewrite() {
+        let algo = S3Algo::new(testing_sdk_client().await);
+        let dir = format!("{}/", rand_string(14));
+        for name in &["a.json", "b.json", "c.txt"] {
+            algo.s3
+                .put_object()
+                .bucket("test-bucket")
+                .key(format!("{}{}", dir, name))
+                .body(b"{}".to_vec().into())
+                .content_type("application/octet-stream")
+                .content_language("en")
+                .metadata("origin", "upload")
+                .storage_class(StorageClass::ReducedRedundancy)
+                .send()
+                .await
+                .unwrap();
+        }
+        let head = |key: String| {
+            let s3 = algo.s3.clone();
+            async move {
+                s3.head_object()
+                    .bucket("test-bucket")
+                    .key(key)
+                    .send()
+                    .await
+                    .unwrap()
+            }
+        };
+
+        // Fix the content type of the JSON files in place, keeping the other metadata
+        algo.list_prefix("test-bucket".into(), Some(dir.clone()))
+            .copy_rewrite(
+                None,
+                true,
+                |obj, head| {
+                    let key = obj.key.clone()?;
+                    if !key.ends_with(".json") {
+                        return None;
+                    }
+                    Some(Rewrite {
+                        content_type: Some("application/json".into()),
+                        ..Rewrite::from_head(key, head?)
+                    })
+                },
+                |_| async {},
+            )
+            .await
+            .unwrap();
+        for name in &["a.json", "b.json"] {
+            let head = head(format!("{}{}", dir, name)).await;
+            assert_eq!(head.content_type.as_deref(), Some("application/json"));
+            assert_eq!(head.content_language.as_deref(), Some("en"));
+            assert_eq!(head.metadata.unwrap()["origin"], "upload");
+            assert_eq!(head.storage_class, Some(StorageClass::ReducedRedundancy));
+        }
+        let skipped = head(format!("{}c.txt", dir)).await;
+        assert_eq!(
+            skipped.content_type.as_deref(),
+            Some("application/octet-stream")
+        );
+
+        // Copy to another prefix with new metadata and tags, without HeadObject
+        let dir2 = dir.clone();
+        algo.list_prefix("test-bucket".into(), Some(dir.clone()))
+            .copy_rewrite(
+                None,
+                false,
+                move |obj, head| {
+                    assert!(head.is_none());
+                    let key = obj.key.as_deref()?;
+                    Some(Rewrite {
+                        key: format!("{}copy/{}", dir2, key.strip_prefix(&dir2)?),
+                        cache_control: Some("max-age=60".into()),
+                        tags: Some(vec![("copied".into(), "yes".into())].into_iter().collect()),
+                        ..Default::default()
+                    })
+                },
+                |_| async {},
+            )
+            .await
+            .unwrap();
+        let copy = format!("{}copy/c.txt", dir);
+        let copied = head(copy.clone()).await;
+        assert_eq!(copied.cache_control.as_deref(), Some("max-age=60"));
+        assert_eq!(copied.storage_class, None);
+        assert!(copied.metadata.unwrap_or_default().is_empty());
+        let tags = algo
+            .s3
+            .get_object_tagging()
+            .bucket("test-bucket")
+            .key(copy)
+            .send()
+            .await
+            .unwrap()
+            .tag_set
+            .unwrap();
+        assert_eq!(tags[0].key.as_deref(), Some("copied"));
+    }
+}
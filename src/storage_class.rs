@@ -0,0 +1,151 @@
+//! Changing the storage class of listed objects by copying them onto themselves.
+use super::*;
+use crate::copy::copy_objects;
+use crate::glacier::is_archived;
+use aws_sdk_s3::operation::list_objects_v2::ListObjectsV2Output;
+use aws_sdk_s3::types::{MetadataDirective, Object, StorageClass};
+use std::collections::HashMap;
+
+/// The storage class of a listed object. Objects without storage class are `STANDARD`.
+fn storage_class_of(obj: &Object) -> &str {
+    obj.storage_class
+        .as_ref()
+        .map_or("STANDARD", |class| class.as_str())
+}
+
+impl<S> ListObjects<S>
+where
+    S: Stream<Item = Result<ListObjectsV2Output, Error>> + Sized + Send + 'static,
+{
+    /// Change the storage class of all listed objects to `storage_class`, by copying each object
+    /// onto itself. Objects that are already in `storage_class` are skipped, and so are objects in
+    /// GLACIER or DEEP_ARCHIVE, which can't be copied without a restore (see `request_restore`).
+    ///
+    /// Without `metadata`, the metadata of the objects is kept (`MetadataDirective::Copy`). With
+    /// `metadata`, it replaces the user-defined metadata (`x-amz-meta-*`) of every copied object
+    /// (`MetadataDirective::Replace`); the content headers such as `Content-Type` are then read
+    /// with a HeadObject request first, and kept. Tags are kept in both cases.
+    ///
+    /// Objects larger than 5 GiB are copied in parts, and each copy or part is retried on its own.
+    ///
+    /// `progress` is called with the `RequestReport` of each copied object, where `size` is the
+    /// size of the object in bytes.
+    pub fn set_storage_class<P, F>(
+        self,
+        storage_class: StorageClass,
+        metadata: Option<HashMap<String, String>>,
+        progress: P,
+    ) -> impl Future<Output = Result<(), Error>>
+    where
+        P: Fn(RequestReport) -> F + Clone + Send + Sync + 'static,
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let target = storage_class.as_str().to_owned();
+        let listing = self.filter(move |obj| storage_class_of(obj) != target && !is_archived(obj));
+        let (s3, config, encryption, bucket) = (
+            listing.s3.clone(),
+            listing.config.clone(),
+            listing.encryption.clone(),
+            listing.bucket.clone(),
+        );
+        let request = {
+            let (s3, bucket) = (s3.clone(), bucket.clone());
+            move |obj: &Object| {
+                s3.copy_object()
+                    .bucket(bucket.clone())
+                    .key(obj.key.clone().unwrap_or_default())
+                    .storage_class(storage_class.clone())
+            }
+        };
+        let requests = match metadata {
+            None => listing
+                .flatten()
+                .map_ok(move |obj| {
+                    let request = request(&obj).metadata_directive(MetadataDirective::Copy);
+                    (obj, request)
+                })
+                .boxed(),
+            Some(metadata) => listing
+                .head_all(false)
+                .map_ok(move |ObjectMetadata { object, head, .. }| {
+                    let request = request(&object)
+                        .metadata_directive(MetadataDirective::Replace)
+                        .set_metadata(Some(metadata.clone()))
+                        .set_content_type(head.content_type)
+                        .set_cache_control(head.cache_control)
+                        .set_content_disposition(head.content_disposition)
+                        .set_content_encoding(head.content_encoding)
+                        .set_content_language(head.content_language)
+                        .set_expires(head.expires);
+                    (object, request)
+                })
+                .boxed(),
+        };
+        copy_objects(s3, &config, encryption, bucket, requests)
+            .try_for_each(move |(_, report)| progress(report).map(Ok))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::rand_string;
+
+    #[tokio::test]
+    async fn test_s3_set_storage_class() {
+        let algo = S3Algo::new(testing_sdk_client().await);
+        let dir = format!("{}/", rand_string(14));
+        for (i, class) in [StorageClass::Standard, StorageClass::ReducedRedundancy]
+            .iter()
+            .enumerate()
+        {
+            algo.s3
+                .put_object()
+                .bucket("test-bucket")
+                .key(format!("{}{}", dir, i))
+                .body(vec![0u8; 10].into())
+                .content_type("text/plain")
+                .metadata("old", "yes")
+                .storage_class(class.clone())
+                .send()
+                .await
+                .unwrap();
+        }
+
+        let n_copied = Arc::new(std::sync::Mutex::new(0));
+        let n_copied2 = n_copied.clone();
+        let metadata = vec![("new".to_owned(), "yes".to_owned())]
+            .into_iter()
+            .collect();
+        algo.list_prefix("test-bucket".into(), Some(dir.clone()))
+            .set_storage_class(
+                StorageClass::ReducedRedundancy,
+                Some(metadata),
+                move |report| {
+                    *n_copied2.lock().unwrap() += 1;
+                    assert_eq!(report.size, 10);
+                    async {}
+                },
+            )
+            .await
+            .unwrap();
+        assert_eq!(*n_copied.lock().unwrap(), 1);
+
+        let head = |i| {
+            algo.s3
+                .head_object()
+                .bucket("test-bucket")
+                .key(format!("{}{}", dir, i))
+                .send()
+        };
+        let copied = head(0).await.unwrap();
+        assert_eq!(copied.storage_class, Some(StorageClass::ReducedRedundancy));
+        assert_eq!(copied.content_type.as_deref(), Some("text/plain"));
+        let copied_metadata = copied.metadata.unwrap();
+        assert_eq!(copied_metadata.get("new").map(String::as_str), Some("yes"));
+        assert!(!copied_metadata.contains_key("old"));
+        // Already in the target class, so not copied
+        let skipped = head(1).await.unwrap();
+        assert!(skipped.metadata.unwrap().contains_key("old"));
+    }
+}
@@ -0,0 +1,176 @@
+//! Aggregated usage statistics of a listing, like `du`.
+use super::*;
+use aws_sdk_s3::operation::list_objects_v2::ListObjectsV2Output;
+use aws_sdk_s3::primitives::DateTime;
+use aws_sdk_s3::types::Object;
+use std::collections::BTreeMap;
+
+/// Number of objects and their total size.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Usage {
+    pub count: u64,
+    pub bytes: u64,
+}
+
+impl Usage {
+    fn add(&mut self, size: u64) {
+        self.count += 1;
+        self.bytes += size;
+    }
+}
+
+/// Statistics of the objects in a listing, computed by `ListObjects::summarize`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Summary {
+    pub total: Usage,
+    /// Number of objects by size. The key is the smallest power of 10 that is greater than the
+    /// size in bytes, so `1000` counts the objects of 100 to 999 bytes.
+    pub size_histogram: BTreeMap<u64, u64>,
+    pub oldest: Option<DateTime>,
+    pub newest: Option<DateTime>,
+    /// Usage by storage class. Objects without storage class count as `STANDARD`.
+    pub by_storage_class: BTreeMap<String, Usage>,
+    /// Usage by folder: the listed prefix followed by up to `depth` more path segments (see
+    /// `ListObjects::summarize`), ending with `/` unless it is the listed prefix itself.
+    pub by_folder: BTreeMap<String, Usage>,
+}
+
+impl Summary {
+    fn add(&mut self, obj: &Object, prefix: &str, depth: usize) {
+        let size = obj.size.max(0) as u64;
+        self.total.add(size);
+
+        let mut bucket = 1;
+        while bucket <= size {
+            bucket *= 10;
+        }
+        *self.size_histogram.entry(bucket).or_default() += 1;
+
+        if let Some(modified) = obj.last_modified {
+            self.oldest = Some(self.oldest.map_or(modified, |oldest| oldest.min(modified)));
+            self.newest = Some(self.newest.map_or(modified, |newest| newest.max(modified)));
+        }
+
+        let class = obj
+            .storage_class
+            .as_ref()
+            .map_or("STANDARD", |class| class.as_str());
+        self.by_storage_class
+            .entry(class.to_owned())
+            .or_default()
+            .add(size);
+
+        let key = obj.key.as_deref().unwrap_or_default();
+        self.by_folder
+            .entry(folder(key, prefix, depth))
+            .or_default()
+            .add(size);
+    }
+}
+
+/// The folder of `key`: `prefix` and at most `depth` of the following path segments, not counting
+/// the file name.
+fn folder(key: &str, prefix: &str, depth: usize) -> String {
+    let rest = key.strip_prefix(prefix).unwrap_or(key);
+    let dirs = match rest.rfind('/') {
+        Some(i) => &rest[..=i],
+        None => "",
+    };
+    let len = dirs
+        .match_indices('/')
+        .take(depth)
+        .last()
+        .map_or(0, |(i, _)| i + 1);
+    format!("{}{}", prefix, &dirs[..len])
+}
+
+impl<S> ListObjects<S>
+where
+    S: Stream<Item = Result<ListObjectsV2Output, Error>> + Sized + Send + 'static,
+{
+    /// Compute usage statistics of all listed objects, without keeping the objects in memory.
+    ///
+    /// The usage by folder groups keys by the listed prefix and the next `depth` path segments.
+    /// For example with prefix `logs/` and `depth` 1, `logs/2023/01/a.gz` is counted under
+    /// `logs/2023/`, and `logs/b.gz` under `logs/`.
+    pub async fn summarize(self, depth: usize) -> Result<Summary, Error> {
+        let prefix = self.prefix.clone();
+        self.flatten()
+            .try_fold(Summary::default(), |mut summary, obj| {
+                summary.add(&obj, &prefix, depth);
+                future::ready(Ok(summary))
+            })
+            .await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use aws_sdk_s3::types::ObjectStorageClass;
+
+    #[test]
+    fn folders() {
+        assert_eq!(folder("logs/2023/01/a.gz", "logs/", 1), "logs/2023/");
+        assert_eq!(folder("logs/2023/01/a.gz", "logs/", 2), "logs/2023/01/");
+        assert_eq!(folder("logs/2023/01/a.gz", "logs/", 5), "logs/2023/01/");
+        assert_eq!(folder("logs/2023/01/a.gz", "logs/", 0), "logs/");
+        assert_eq!(folder("logs/b.gz", "logs/", 1), "logs/");
+        assert_eq!(folder("logs/2023/01/a.gz", "logs/20", 1), "logs/2023/");
+        assert_eq!(folder("a/b", "", 1), "a/");
+    }
+
+    #[tokio::test]
+    async fn summarize() {
+        let object = |key: &str, size, modified, class: Option<ObjectStorageClass>| {
+            Object::builder()
+                .key(key)
+                .size(size)
+                .last_modified(DateTime::from_secs(modified))
+                .set_storage_class(class)
+                .build()
+        };
+        let pages = vec![
+            vec![
+                object("logs/a/1", 5, 300, None),
+                object("logs/a/2", 50, 100, None),
+            ],
+            vec![
+                object("logs/b/1", 500, 200, Some(ObjectStorageClass::Glacier)),
+                object("logs/c", 0, 400, None),
+            ],
+        ];
+        let listing = ListObjects::from_pages("logs/", pages).await;
+        let summary = listing.summarize(1).await.unwrap();
+
+        let usage = |count, bytes| Usage { count, bytes };
+        assert_eq!(summary.total, usage(4, 555));
+        assert_eq!(
+            summary.size_histogram,
+            vec![(1, 1), (10, 1), (100, 1), (1000, 1)]
+                .into_iter()
+                .collect()
+        );
+        assert_eq!(summary.oldest, Some(DateTime::from_secs(100)));
+        assert_eq!(summary.newest, Some(DateTime::from_secs(400)));
+        assert_eq!(
+            summary.by_storage_class,
+            vec![
+                ("GLACIER".to_owned(), usage(1, 500)),
+                ("STANDARD".to_owned(), usage(3, 55)),
+            ]
+            .into_iter()
+            .collect()
+        );
+        assert_eq!(
+            summary.by_folder,
+            vec![
+                ("logs/".to_owned(), usage(1, 0)),
+                ("logs/a/".to_owned(), usage(2, 55)),
+                ("logs/b/".to_owned(), usage(1, 500)),
+            ]
+            .into_iter()
+            .collect()
+        );
+    }
+}
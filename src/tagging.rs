@@ -0,0 +1,223 @@
+//! Setting, merging and removing the tags of all listed objects.
+use super::*;
+use aws_sdk_s3::operation::list_objects_v2::ListObjectsV2Output;
+use aws_sdk_s3::types::{Tag, Tagging};
+use std::collections::BTreeMap;
+
+/// How `ListObjects::tag_all` changes the tags of each object.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TagUpdate {
+    /// Replace all tags with these. An empty map removes all tags.
+    Set(BTreeMap<String, String>),
+    /// Add these tags, overwriting existing tags with the same keys, and keep the other tags.
+    Merge(BTreeMap<String, String>),
+    /// Remove the tags with these keys, and keep the other tags.
+    Remove(Vec<String>),
+}
+
+impl TagUpdate {
+    /// Whether the current tags must be read to apply the update.
+    fn needs_current(&self) -> bool {
+        !matches!(self, Self::Set(_))
+    }
+
+    /// The new tags of an object with tags `current`.
+    fn apply(&self, mut current: BTreeMap<String, String>) -> BTreeMap<String, String> {
+        match self {
+            Self::Set(tags) => tags.clone(),
+            Self::Merge(tags) => {
+                current.extend(tags.clone());
+                current
+            }
+            Self::Remove(keys) => {
+                for key in keys {
+                    current.remove(key);
+                }
+                current
+            }
+        }
+    }
+}
+
+/// Read, update and write the tags of one object.
+async fn update_tags(
+    s3: &Client,
+    bucket: &str,
+    key: &str,
+    update: &TagUpdate,
+) -> Result<(), Error> {
+    let current = if update.needs_current() {
+        s3.get_object_tagging()
+            .bucket(bucket)
+            .key(key)
+            .send()
+            .await?
+            .tag_set
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(|tag| Some((tag.key?, tag.value.unwrap_or_default())))
+            .collect()
+    } else {
+        BTreeMap::new()
+    };
+    let tags = update.apply(current);
+    if tags.is_empty() {
+        s3.delete_object_tagging()
+            .bucket(bucket)
+            .key(key)
+            .send()
+            .await?;
+    } else {
+        let tag_set = tags
+            .into_iter()
+            .map(|(key, value)| Tag::builder().key(key).value(value).build())
+            .collect();
+        s3.put_object_tagging()
+            .bucket(bucket)
+            .key(key)
+            .tagging(Tagging::builder().set_tag_set(Some(tag_set)).build())
+            .send()
+            .await?;
+    }
+    Ok(())
+}
+
+impl<S> ListObjects<S>
+where
+    S: Stream<Item = Result<ListObjectsV2Output, Error>> + Sized + Send + 'static,
+{
+    /// Change the tags of all listed objects according to `update`.
+    ///
+    /// Up to `copy_parallelization` objects are tagged at the same time. `progress` is called with
+    /// the `RequestReport` of each object, where `size` is 1. For `TagUpdate::Merge` and
+    /// `TagUpdate::Remove`, the current tags are read first, and a retry repeats both requests.
+    pub fn tag_all<P, F>(
+        self,
+        update: TagUpdate,
+        progress: P,
+    ) -> impl Future<Output = Result<(), Error>>
+    where
+        P: Fn(RequestReport) -> F + Clone + Send + Sync + 'static,
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let (s3, bucket) = (self.s3.clone(), self.bucket.clone());
+        let timeout = Arc::new(Mutex::new(TimeoutState::new(
+            self.config.algorithm.clone(),
+            self.config.delete_requests.clone(),
+        )));
+        let n_retries = self.config.algorithm.n_retries;
+        let parallelization = self.config.copy_parallelization;
+        let update = Arc::new(update);
+        self.flatten()
+            .try_for_each_concurrent(parallelization, move |object| {
+                let (s3, bucket, update, timeout, progress) = (
+                    s3.clone(),
+                    bucket.clone(),
+                    update.clone(),
+                    timeout.clone(),
+                    progress.clone(),
+                );
+                async move {
+                    let key = object.key.ok_or(Error::MissingKeyOrSize)?;
+                    let (report, _) = s3_request(
+                        move || {
+                            let (s3, bucket, key, update) =
+                                (s3.clone(), bucket.clone(), key.clone(), update.clone());
+                            async move {
+                                Ok((
+                                    async move { update_tags(&s3, &bucket, &key, &update).await },
+                                    1,
+                                ))
+                            }
+                        },
+                        |_, size| size,
+                        n_retries,
+                        timeout.clone(),
+                    )
+                    .await?;
+                    timeout.lock().await.update(&report);
+                    progress(report).await;
+                    Ok::<_, Error>(())
+                }
+            })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::rand_string;
+
+    fn tags(tags: &[(&str, &str)]) -> BTreeMap<String, String> {
+        tags.iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn apply_updates() {
+        let current = tags(&[("a", "1"), ("b", "2")]);
+        assert_eq!(
+            TagUpdate::Set(tags(&[("c", "3")])).apply(current.clone()),
+            tags(&[("c", "3")])
+        );
+        assert_eq!(
+            TagUpdate::Merge(tags(&[("b", "3"), ("c", "3")])).apply(current.clone()),
+            tags(&[("a", "1"), ("b", "3"), ("c", "3")])
+        );
+        assert_eq!(
+            TagUpdate::Remove(vec!["a".into(), "x".into()]).apply(current),
+            tags(&[("b", "2")])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_s3_tag_all() {
+        let algo = S3Algo::new(testing_sdk_client().await);
+        let dir = format!("{}/", rand_string(14));
+        for i in 0..5 {
+            algo.s3
+                .put_object()
+                .bucket("test-bucket")
+                .key(format!("{}{}", dir, i))
+                .tagging("keep=yes&drop=yes")
+                .send()
+                .await
+                .unwrap();
+        }
+        let tag_all = |update| {
+            algo.list_prefix("test-bucket".into(), Some(dir.clone()))
+                .tag_all(update, |_| async {})
+        };
+        let object_tags = |i| {
+            let s3 = algo.s3.clone();
+            let key = format!("{}{}", dir, i);
+            async move {
+                s3.get_object_tagging()
+                    .bucket("test-bucket")
+                    .key(key)
+                    .send()
+                    .await
+                    .unwrap()
+                    .tag_set
+                    .unwrap_or_default()
+                    .into_iter()
+                    .map(|tag| (tag.key.unwrap(), tag.value.unwrap()))
+                    .collect::<BTreeMap<_, _>>()
+            }
+        };
+
+        tag_all(TagUpdate::Merge(tags(&[("new", "1")])))
+            .await
+            .unwrap();
+        tag_all(TagUpdate::Remove(vec!["drop".into()]))
+            .await
+            .unwrap();
+        for i in 0..5 {
+            assert_eq!(object_tags(i).await, tags(&[("keep", "yes"), ("new", "1")]));
+        }
+
+        tag_all(TagUpdate::Set(BTreeMap::new())).await.unwrap();
+        assert!(object_tags(0).await.is_empty());
+    }
+}
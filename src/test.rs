@@ -1,4 +1,6 @@
 use crate::*;
+use aws_sdk_s3::operation::list_objects_v2::ListObjectsV2Output;
+use aws_sdk_s3::types::Object;
 use rand::Rng;
 use std::path::Path;
 use std::sync::Arc;
@@ -29,6 +31,66 @@ pub(crate) fn rand_string(n: usize) -> String {
         .collect::<String>()
 }
 
+/// The listing stream of `ListObjects::from_pages`.
+pub(crate) type FixedPages = stream::Iter<std::vec::IntoIter<Result<ListObjectsV2Output, Error>>>;
+
+impl ListObjects<FixedPages> {
+    /// A listing of `prefix` in `test-bucket` over fixed pages of objects, without any requests to
+    /// S3.
+    pub(crate) async fn from_pages(prefix: &str, pages: Vec<Vec<Object>>) -> Self {
+        let algo = S3Algo::new(testing_sdk_client().await);
+        let pages = pages
+            .into_iter()
+            .map(|objects| {
+                Ok(ListObjectsV2Output::builder()
+                    .key_count(objects.len() as i32)
+                    .set_contents(Some(objects))
+                    .build())
+            })
+            .collect::<Vec<_>>();
+        ListObjects {
+            s3: algo.s3.clone(),
+            config: algo.config.clone(),
+            bucket: "test-bucket".into(),
+            prefix: prefix.to_owned(),
+            encryption: Encryption::None,
+            stream: stream::iter(pages),
+        }
+    }
+}
+
+/// Create (if needed) a bucket with versioning enabled, and return its name.
+pub(crate) async fn versioned_test_bucket(s3: &Client) -> String {
+    use aws_sdk_s3::types::{BucketVersioningStatus, VersioningConfiguration};
+    let bucket = "test-bucket-versioned".to_owned();
+    // Fails if the bucket already exists
+    let _ = s3.create_bucket().bucket(&bucket).send().await;
+    s3.put_bucket_versioning()
+        .bucket(&bucket)
+        .versioning_configuration(
+            VersioningConfiguration::builder()
+                .status(BucketVersioningStatus::Enabled)
+                .build(),
+        )
+        .send()
+        .await
+        .unwrap();
+    bucket
+}
+
+/// Create (if needed) a bucket with Object Lock enabled, and return its name.
+pub(crate) async fn locked_test_bucket(s3: &Client) -> String {
+    let bucket = "test-bucket-locked".to_owned();
+    // Fails if the bucket already exists
+    let _ = s3
+        .create_bucket()
+        .bucket(&bucket)
+        .object_lock_enabled_for_bucket(true)
+        .send()
+        .await;
+    bucket
+}
+
 #[test]
 fn everything_is_sync_and_static() {
     // This is only to test that it compiles
@@ -0,0 +1,185 @@
+//! Transforming objects: download, process and upload the result, like a `map` over a listing.
+use super::*;
+use crate::list_actions::download_object;
+use aws_sdk_s3::operation::list_objects_v2::ListObjectsV2Output;
+use aws_sdk_s3::operation::put_object::builders::PutObjectFluentBuilder;
+use aws_sdk_s3::types::Object;
+
+impl<S> ListObjects<S>
+where
+    S: Stream<Item = Result<ListObjectsV2Output, Error>> + Sized + Send + 'static,
+{
+    /// Download every listed object, pass it to `transform`, and upload the result to
+    /// `dest_bucket` (or the same bucket) under the key given by `mapping`. `transform` returns
+    /// `None` to skip an object.
+    ///
+    /// Both the download and the upload are retried with the timeouts of `put_requests`. Up to
+    /// `copy_parallelization` objects are processed at the same time, and each holds its object
+    /// and the result in memory, which bounds the memory usage.
+    ///
+    /// `default_request` constructs the default upload request, like for `S3Algo::upload_files`,
+    /// for example to set the content type of the results. `progress` is called with the
+    /// `RequestReport` of each upload.
+    pub fn transform_all<M, T, Fut, R, P, F>(
+        self,
+        dest_bucket: Option<String>,
+        mapping: M,
+        transform: T,
+        default_request: R,
+        progress: P,
+    ) -> impl Future<Output = Result<(), Error>>
+    where
+        M: Fn(&str) -> String + Send + Sync + 'static,
+        T: Fn(Object, Vec<u8>) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<Option<Vec<u8>>, Error>> + Send + 'static,
+        R: Fn(&Client) -> PutObjectFluentBuilder + Clone + Unpin + Sync + Send + 'static,
+        P: Fn(RequestReport) -> F + Clone + Send + Sync + 'static,
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let (s3, encryption, bucket) = (
+            self.s3.clone(),
+            self.encryption.clone(),
+            self.bucket.clone(),
+        );
+        let dest_bucket = dest_bucket.unwrap_or_else(|| bucket.clone());
+        let download_timeout = Arc::new(Mutex::new(TimeoutState::new(
+            self.config.algorithm.clone(),
+            self.config.put_requests.clone(),
+        )));
+        let upload_timeout = Arc::new(Mutex::new(TimeoutState::new(
+            self.config.algorithm.clone(),
+            self.config.put_requests.clone(),
+        )));
+        let n_retries = self.config.algorithm.n_retries;
+        let parallelization = self.config.copy_parallelization;
+        let encryption2 = encryption.clone();
+        let default_request = move |s3: &Client| encryption2.put_object(default_request(s3));
+        let transform = Arc::new(transform);
+
+        self.flatten()
+            .map_ok(move |obj| {
+                let (s3, encryption, bucket, dest_bucket, default_request) = (
+                    s3.clone(),
+                    encryption.clone(),
+                    bucket.clone(),
+                    dest_bucket.clone(),
+                    default_request.clone(),
+                );
+                let (download_timeout, upload_timeout, transform) = (
+                    download_timeout.clone(),
+                    upload_timeout.clone(),
+                    transform.clone(),
+                );
+                let dest_key = obj.key.as_deref().map(&mapping);
+                async move {
+                    let (key, dest_key) = match (obj.key.clone(), dest_key) {
+                        (Some(key), Some(dest_key)) => (key, dest_key),
+                        _ => return Err(Error::MissingKeyOrSize),
+                    };
+                    let (_, data) = download_object(
+                        s3.clone(),
+                        encryption,
+                        bucket,
+                        key,
+                        obj.size as usize,
+                        n_retries,
+                        download_timeout,
+                    )
+                    .await?;
+                    let data = match transform(obj, data).await? {
+                        Some(data) => data,
+                        None => return Ok(None),
+                    };
+                    let (report, _) = s3_request(
+                        move || {
+                            ObjectSource::data(data.clone(), dest_key.clone()).create_upload_future(
+                                s3.clone(),
+                                dest_bucket.clone(),
+                                default_request.clone(),
+                            )
+                        },
+                        |_, size| size,
+                        n_retries,
+                        upload_timeout.clone(),
+                    )
+                    .await?;
+                    upload_timeout.lock().await.update(&report);
+                    Ok(Some(report))
+                }
+            })
+            .try_buffer_unordered(parallelization)
+            .try_filter_map(future::ok)
+            .try_for_each(move |report| progress(report).map(Ok))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::rand_string;
+
+    #[tokio::test]
+    async fn test_s3_transform_all() {
+        let algo = S3Algo::new(testing_sdk_client().await);
+        let dir = format!("{}/", rand_string(14));
+        let files = vec![
+            ObjectSource::data("hello", format!("{}in/a.txt", dir)),
+            ObjectSource::data("world", format!("{}in/b.txt", dir)),
+            ObjectSource::data("skip", format!("{}in/c.bin", dir)),
+        ];
+        algo.upload_files(
+            "test-bucket".into(),
+            files.into_iter(),
+            |_| async {},
+            |client| client.put_object(),
+        )
+        .await
+        .unwrap();
+
+        let n_uploaded = Arc::new(std::sync::Mutex::new(0));
+        let n_uploaded2 = n_uploaded.clone();
+        algo.list_prefix("test-bucket".into(), Some(format!("{}in/", dir)))
+            .transform_all(
+                None,
+                |key| key.replacen("/in/", "/out/", 1),
+                |obj, data| async move {
+                    if obj.key.unwrap().ends_with(".txt") {
+                        Ok(Some(data.to_ascii_uppercase()))
+                    } else {
+                        Ok(None)
+                    }
+                },
+                |client| client.put_object().content_type("text/plain"),
+                move |_| {
+                    *n_uploaded2.lock().unwrap() += 1;
+                    async {}
+                },
+            )
+            .await
+            .unwrap();
+        assert_eq!(*n_uploaded.lock().unwrap(), 2);
+
+        let outputs = algo
+            .list_prefix("test-bucket".into(), Some(format!("{}out/", dir)))
+            .download_all_to_vec()
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap();
+        assert_eq!(
+            outputs,
+            vec![
+                (format!("{}out/a.txt", dir), b"HELLO".to_vec()),
+                (format!("{}out/b.txt", dir), b"WORLD".to_vec()),
+            ]
+        );
+        let head = algo
+            .s3
+            .head_object()
+            .bucket("test-bucket")
+            .key(format!("{}out/a.txt", dir))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(head.content_type.as_deref(), Some("text/plain"));
+    }
+}
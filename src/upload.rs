@@ -1,6 +1,16 @@
 use super::*;
 use aws_sdk_s3::operation::put_object::builders::PutObjectFluentBuilder;
 use aws_sdk_s3::primitives::ByteStream;
+use aws_smithy_http::body::SdkBody;
+use aws_smithy_http::byte_stream::Length;
+use bytes::Bytes;
+use http_body::combinators::BoxBody;
+use std::fmt;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::Path;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::sync::mpsc;
 
 impl S3Algo {
     /// Upload multiple files to S3.
@@ -19,6 +29,8 @@ impl S3Algo {
     ///
     /// `default_request` constructs the default request struct - only the fields `bucket`, `key`,
     /// `body` and `content_length` are overwritten by the upload algorithm.
+    /// The encryption settings of `self` (see `S3Algo::with_encryption`) are applied on top of
+    /// `default_request`.
     pub async fn upload_files<P, F, I, R>(
         &self,
         bucket: String,
@@ -31,6 +43,49 @@ impl S3Algo {
         F: Future<Output = ()> + Send + 'static,
         I: Iterator<Item = ObjectSource> + Send + 'static,
         R: Fn(&Client) -> PutObjectFluentBuilder + Clone + Unpin + Sync + Send + 'static,
+    {
+        self.upload_stream(
+            bucket,
+            stream::iter(files).map(Ok),
+            progress,
+            default_request,
+        )
+        .await
+    }
+
+    /// Like `upload_files`, but for object sources that may fail to be produced, such as the
+    /// files of [`FilesRecursive`](struct.FilesRecursive.html). The first error aborts the upload.
+    pub async fn try_upload_files<P, F, I, R>(
+        &self,
+        bucket: String,
+        files: I,
+        progress: P,
+        default_request: R,
+    ) -> Result<(), Error>
+    where
+        P: Fn(RequestReport) -> F + Clone + Send + Sync + 'static,
+        F: Future<Output = ()> + Send + 'static,
+        I: Iterator<Item = Result<ObjectSource, Error>> + Send + 'static,
+        R: Fn(&Client) -> PutObjectFluentBuilder + Clone + Unpin + Sync + Send + 'static,
+    {
+        self.upload_stream(bucket, stream::iter(files), progress, default_request)
+            .await
+    }
+
+    /// Like `upload_files`, but the object sources are produced by a fallible stream, such as
+    /// [`files_from_tar`](files_from_tar). An error in the stream aborts the upload.
+    pub async fn upload_stream<P, F, S, R>(
+        &self,
+        bucket: String,
+        files: S,
+        progress: P,
+        default_request: R,
+    ) -> Result<(), Error>
+    where
+        P: Fn(RequestReport) -> F + Clone + Send + Sync + 'static,
+        F: Future<Output = ()> + Send + 'static,
+        S: Stream<Item = Result<ObjectSource, Error>> + Send + 'static,
+        R: Fn(&Client) -> PutObjectFluentBuilder + Clone + Unpin + Sync + Send + 'static,
     {
         let copy_parallelization = self.config.copy_parallelization;
         let n_retries = self.config.algorithm.n_retries;
@@ -41,7 +96,10 @@ impl S3Algo {
         )));
         let timeout_state2 = timeout_state.clone();
 
-        let jobs = files.map(move |src| {
+        let encryption = self.encryption.clone();
+        let default_request = move |s3: &Client| encryption.put_object(default_request(s3));
+
+        let jobs = files.map_ok(move |src| {
             let (default, bucket, s3) = (default_request.clone(), bucket.clone(), self.s3.clone());
             s3_request(
                 move || {
@@ -58,8 +116,7 @@ impl S3Algo {
         // Run jobs in parallel,
         //  adding eventual delays after each file upload and also at the end,
         //  and counting the progress
-        stream::iter(jobs)
-            .buffer_unordered(copy_parallelization)
+        jobs.try_buffer_unordered(copy_parallelization)
             .zip(stream::iter(0..))
             .map(|(result, i)| result.map(|result| (i, result)))
             .try_for_each(move |(i, (mut result, _))| {
@@ -77,9 +134,62 @@ impl S3Algo {
 
 #[derive(Clone, Debug)]
 pub enum ObjectSource {
-    File { path: PathBuf, key: String },
-    Data { data: Vec<u8>, key: String },
+    File {
+        path: PathBuf,
+        key: String,
+    },
+    Data {
+        data: Vec<u8>,
+        key: String,
+    },
+    /// `len` bytes of a file, starting at `offset`. For example an entry of a tar archive.
+    FileRange {
+        path: PathBuf,
+        offset: u64,
+        len: u64,
+        key: String,
+    },
+    /// `len` bytes that are read as they are uploaded, for example a large entry of a compressed
+    /// tar archive. It can only be uploaded once, so a failed upload is not retried.
+    Streamed {
+        body: StreamedBody,
+        len: u64,
+        key: String,
+    },
+}
+
+/// The body of an `ObjectSource::Streamed`, produced by [`files_from_tar`](fn.files_from_tar.html).
+#[derive(Clone)]
+pub struct StreamedBody(Arc<std::sync::Mutex<Option<mpsc::Receiver<io::Result<Bytes>>>>>);
+
+impl fmt::Debug for StreamedBody {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("StreamedBody")
+    }
 }
+
+/// The chunks of a `StreamedBody` as an HTTP body.
+struct ChannelBody(mpsc::Receiver<io::Result<Bytes>>);
+
+impl http_body::Body for ChannelBody {
+    type Data = Bytes;
+    type Error = io::Error;
+
+    fn poll_data(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Bytes, io::Error>>> {
+        self.0.poll_recv(cx)
+    }
+
+    fn poll_trailers(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+    ) -> Poll<Result<Option<http::HeaderMap>, io::Error>> {
+        Poll::Ready(Ok(None))
+    }
+}
+
 impl ObjectSource {
     pub fn file(path: PathBuf, key: String) -> Self {
         Self::File { path, key }
@@ -115,6 +225,34 @@ impl ObjectSource {
                 Ok((ByteStream::read_from().file(file).build().await?, len))
             }
             Self::Data { data, .. } => Ok((data.clone().into(), data.len())),
+            Self::FileRange {
+                path, offset, len, ..
+            } => {
+                let file = tokio::fs::File::open(path.clone()).await.with_context({
+                    let path = path.clone();
+                    move || err::Io {
+                        description: path.display().to_string(),
+                    }
+                })?;
+                let stream = ByteStream::read_from()
+                    .file(file)
+                    .offset(*offset)
+                    .length(Length::Exact(*len))
+                    .build()
+                    .await?;
+                Ok((stream, *len as usize))
+            }
+            Self::Streamed { body, len, key } => {
+                let chunks = body
+                    .0
+                    .lock()
+                    .unwrap()
+                    .take()
+                    .ok_or_else(|| Error::BodyConsumed { key: key.clone() })?;
+                let body =
+                    BoxBody::new(http_body::Body::map_err(ChannelBody(chunks), |e| e.into()));
+                Ok((ByteStream::new(SdkBody::from_dyn(body)), *len as usize))
+            }
         }
     }
     pub async fn create_upload_future<R>(
@@ -147,6 +285,8 @@ impl ObjectSource {
         match self {
             Self::File { key, .. } => key,
             Self::Data { key, .. } => key,
+            Self::FileRange { key, .. } => key,
+            Self::Streamed { key, .. } => key,
         }
     }
 }
@@ -154,24 +294,35 @@ impl ObjectSource {
 /// Convenience function (using `walkdir`) to traverse all files in directory `src_dir`. Returns an
 /// iterator that can be used as input to `S3Algo::upload_files`, which uploads files
 /// with a key equal to the file's path with `src_dir` stripped away, and with `key_prefix`
-/// prepended.
+/// prepended. Keys always use `/` as separator, see the default [`KeyMapping`](struct.KeyMapping.html).
+///
+/// Files that can not be read while traversing `src_dir` are silently skipped. Use
+/// [`FilesRecursive`](struct.FilesRecursive.html) to get these errors, to filter the files, or to
+/// choose another key mapping.
 pub fn files_recursive(
     src_dir: PathBuf,
     key_prefix: PathBuf,
 ) -> impl Iterator<Item = ObjectSource> {
+    let key_prefix = key_prefix_string(&key_prefix);
+    let mapping = KeyMapping::default();
     walkdir::WalkDir::new(&src_dir)
         .into_iter()
         .filter_map(move |entry| {
             let src_dir = src_dir.clone();
             let key_prefix = key_prefix.clone();
+            let mapping = mapping.clone();
             entry.ok().and_then(move |entry| {
                 if entry.file_type().is_file() {
                     let path = entry.path().to_owned();
-                    let key_suffix = path.strip_prefix(&src_dir).unwrap().to_path_buf();
-                    let key = key_prefix.join(&key_suffix);
+                    let key_suffix = path.strip_prefix(&src_dir).unwrap();
+                    // Only fails for paths with components other than names, which a path below
+                    // `src_dir` does not have
+                    let key = mapping
+                        .key(key_suffix)
+                        .expect("the default key mapping accepts relative paths");
                     Some(ObjectSource::File {
                         path,
-                        key: key.to_string_lossy().to_string(),
+                        key: format!("{}{}", key_prefix, key),
                     })
                 } else {
                     None
@@ -180,6 +331,313 @@ pub fn files_recursive(
         })
 }
 
+/// `key_prefix` as a string with `/` as separator, ending with `/` unless it is empty.
+fn key_prefix_string(key_prefix: &Path) -> String {
+    let mut prefix = key_prefix
+        .to_string_lossy()
+        .replace(std::path::MAIN_SEPARATOR, "/");
+    if !prefix.is_empty() && !prefix.ends_with('/') {
+        prefix.push('/');
+    }
+    prefix
+}
+
+/// Builder for a filtered traversal of all files in a directory, like
+/// [`files_recursive`](fn.files_recursive.html). Without any options, the same files are
+/// visited, but errors are reported instead of skipped.
+///
+/// ```no_run
+/// # fn main() -> Result<(), s3_algo::Error> {
+/// use s3_algo::FilesRecursive;
+/// let files = FilesRecursive::new("data".into(), "prefix".into())
+///     .include("*.jpg")
+///     .exclude("tmp/**")
+///     .git_ignore(true)
+///     .build()?;
+/// # Ok(()) }
+/// ```
+#[derive(Clone, Debug)]
+pub struct FilesRecursive {
+    src_dir: PathBuf,
+    key_prefix: PathBuf,
+    include: Vec<String>,
+    exclude: Vec<String>,
+    git_ignore: bool,
+    ignore_file_names: Vec<String>,
+    max_depth: Option<usize>,
+    follow_links: bool,
+    hidden: bool,
+    min_size: Option<u64>,
+    max_size: Option<u64>,
+    key_mapping: KeyMapping,
+}
+
+impl FilesRecursive {
+    pub fn new(src_dir: PathBuf, key_prefix: PathBuf) -> Self {
+        Self {
+            src_dir,
+            key_prefix,
+            include: vec![],
+            exclude: vec![],
+            git_ignore: false,
+            ignore_file_names: vec![],
+            max_depth: None,
+            follow_links: false,
+            hidden: true,
+            min_size: None,
+            max_size: None,
+            key_mapping: KeyMapping::default(),
+        }
+    }
+    /// Only visit files whose path relative to `src_dir` matches one of the include globs.
+    /// Note that `*` also matches `/`.
+    pub fn include(mut self, glob: &str) -> Self {
+        self.include.push(glob.to_owned());
+        self
+    }
+    /// Skip files whose path relative to `src_dir` matches one of the exclude globs.
+    pub fn exclude(mut self, glob: &str) -> Self {
+        self.exclude.push(glob.to_owned());
+        self
+    }
+    /// Respect `.gitignore` files, also outside of git repositories. Default: `false`.
+    pub fn git_ignore(mut self, yes: bool) -> Self {
+        self.git_ignore = yes;
+        self
+    }
+    /// Respect ignore files with the given name (for example `.s3ignore`), which use the same
+    /// syntax as `.gitignore`.
+    pub fn ignore_file_name(mut self, name: &str) -> Self {
+        self.ignore_file_names.push(name.to_owned());
+        self
+    }
+    /// Only visit entries up to `depth` levels below `src_dir`, so that `Some(1)` only visits the
+    /// files directly in `src_dir`. Default: no limit.
+    pub fn max_depth(mut self, depth: Option<usize>) -> Self {
+        self.max_depth = depth;
+        self
+    }
+    /// Follow symbolic links. Default: `false`.
+    pub fn follow_links(mut self, yes: bool) -> Self {
+        self.follow_links = yes;
+        self
+    }
+    /// Visit hidden files and directories (whose name starts with `.`). Default: `true`.
+    pub fn hidden(mut self, yes: bool) -> Self {
+        self.hidden = yes;
+        self
+    }
+    /// Only visit files of at least `bytes` bytes.
+    pub fn min_size(mut self, bytes: u64) -> Self {
+        self.min_size = Some(bytes);
+        self
+    }
+    /// Only visit files of at most `bytes` bytes.
+    pub fn max_size(mut self, bytes: u64) -> Self {
+        self.max_size = Some(bytes);
+        self
+    }
+
+    /// How to map the path of a file relative to `src_dir` to the part of the key after
+    /// `key_prefix`. Files whose path can not be mapped give an error.
+    pub fn key_mapping(mut self, mapping: KeyMapping) -> Self {
+        self.key_mapping = mapping;
+        self
+    }
+
+    /// Returns an iterator that can be used as input to `S3Algo::try_upload_files`. Fails if one
+    /// of the globs is invalid. Errors during the traversal are returned as `Error::Io` by the
+    /// iterator.
+    pub fn build(self) -> Result<impl Iterator<Item = Result<ObjectSource, Error>>, Error> {
+        let has_include = !self.include.is_empty();
+        let include = glob_set(&self.include)?;
+        let exclude = glob_set(&self.exclude)?;
+
+        let mut walk = ignore::WalkBuilder::new(&self.src_dir);
+        walk.standard_filters(false)
+            .hidden(!self.hidden)
+            .git_ignore(self.git_ignore)
+            .require_git(false)
+            .max_depth(self.max_depth)
+            .follow_links(self.follow_links);
+        for name in &self.ignore_file_names {
+            walk.add_custom_ignore_filename(name);
+        }
+
+        let FilesRecursive {
+            src_dir,
+            key_prefix,
+            min_size,
+            max_size,
+            key_mapping,
+            ..
+        } = self;
+        let key_prefix = key_prefix_string(&key_prefix);
+        let walk_error = {
+            let src_dir = src_dir.clone();
+            move |e: ignore::Error| {
+                let kind = e.io_error().map_or(io::ErrorKind::Other, |e| e.kind());
+                Err(io::Error::new(kind, e)).context(err::Io {
+                    description: src_dir.display().to_string(),
+                })
+            }
+        };
+        Ok(walk.build().filter_map(move |entry| {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(e) => return Some(walk_error(e)),
+            };
+            if !entry.file_type().is_some_and(|t| t.is_file()) {
+                return None;
+            }
+            let path = entry.path().to_owned();
+            let key_suffix = path.strip_prefix(&src_dir).unwrap().to_path_buf();
+            if (has_include && !include.is_match(&key_suffix)) || exclude.is_match(&key_suffix) {
+                return None;
+            }
+            if min_size.is_some() || max_size.is_some() {
+                let size = match entry.metadata() {
+                    Ok(metadata) => metadata.len(),
+                    Err(e) => return Some(walk_error(e)),
+                };
+                if min_size.is_some_and(|min| size < min) || max_size.is_some_and(|max| size > max)
+                {
+                    return None;
+                }
+            }
+            Some(key_mapping.key(&key_suffix).map(|key| ObjectSource::File {
+                key: format!("{}{}", key_prefix, key),
+                path,
+            }))
+        }))
+    }
+}
+
+fn glob_set(globs: &[String]) -> Result<globset::GlobSet, Error> {
+    let mut set = globset::GlobSetBuilder::new();
+    for glob in globs {
+        set.add(globset::Glob::new(glob).context(err::Glob { glob })?);
+    }
+    set.build().context(err::Glob {
+        glob: globs.join(", "),
+    })
+}
+
+/// Entries of a compressed tar archive up to this size are decompressed into memory, so that
+/// their upload can be retried. Larger entries are streamed.
+const MAX_BUFFERED_ENTRY: u64 = 8 * 1024 * 1024;
+/// The size of the chunks of a streamed entry.
+const STREAM_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Stream the regular files in the local tar archive `archive`, which may be gzip-compressed.
+/// Returns a stream that can be used as input to `S3Algo::upload_stream`, which uploads each entry
+/// with a key equal to its path in the archive, with `key_prefix` prepended.
+///
+/// Nothing is unpacked to disk. The archive is read on a blocking thread, which produces the next
+/// entry while earlier ones are uploaded. The entries of an uncompressed archive are uploaded
+/// directly from their byte range in the archive. The entries of a compressed archive are
+/// decompressed as they are read: entries up to 8 MiB are held in memory, while larger entries
+/// are streamed as `ObjectSource::Streamed`. Since the archive is read in order, the next entry is
+/// only produced once a streamed entry is uploaded, and a failed upload of a streamed entry is not
+/// retried.
+pub fn files_from_tar(
+    archive: PathBuf,
+    key_prefix: String,
+) -> impl Stream<Item = Result<ObjectSource, Error>> + Send {
+    let (tx, rx) = mpsc::channel(1);
+    tokio::task::spawn_blocking(move || {
+        if let Err(e) = read_tar_entries(&archive, &key_prefix, &tx) {
+            let _ = tx.blocking_send(Err(e));
+        }
+    });
+    stream::unfold(
+        rx,
+        |mut rx| async move { rx.recv().await.map(|src| (src, rx)) },
+    )
+}
+
+/// Send an `ObjectSource` for every regular file in the archive, until the receiver hangs up.
+fn read_tar_entries(
+    archive: &Path,
+    key_prefix: &str,
+    tx: &mpsc::Sender<Result<ObjectSource, Error>>,
+) -> Result<(), Error> {
+    let context = || err::Io {
+        description: archive.display().to_string(),
+    };
+    let mut file = std::fs::File::open(archive).with_context(context)?;
+    let mut magic = [0u8; 2];
+    let gzip = match file.read_exact(&mut magic) {
+        Ok(()) => magic == [0x1f, 0x8b],
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => false,
+        Err(e) => return Err(e).with_context(context),
+    };
+    file.seek(SeekFrom::Start(0)).with_context(context)?;
+
+    let entry_key = |path: &[u8]| format!("{}{}", key_prefix, String::from_utf8_lossy(path));
+    if gzip {
+        let mut tar = tar::Archive::new(flate2::read::GzDecoder::new(file));
+        for entry in tar.entries().with_context(context)? {
+            let mut entry = entry.with_context(context)?;
+            if !entry.header().entry_type().is_file() {
+                continue;
+            }
+            let key = entry_key(&entry.path_bytes());
+            let len = entry.size();
+            if len <= MAX_BUFFERED_ENTRY {
+                let mut data = Vec::with_capacity(len as usize);
+                entry.read_to_end(&mut data).with_context(context)?;
+                if tx
+                    .blocking_send(Ok(ObjectSource::Data { data, key }))
+                    .is_err()
+                {
+                    return Ok(());
+                }
+                continue;
+            }
+            let (chunks_tx, chunks_rx) = mpsc::channel(4);
+            let body = StreamedBody(Arc::new(std::sync::Mutex::new(Some(chunks_rx))));
+            if tx
+                .blocking_send(Ok(ObjectSource::Streamed { body, len, key }))
+                .is_err()
+            {
+                return Ok(());
+            }
+            // Stop streaming if the upload is dropped, but continue with the next entry, since
+            // the upload may just have failed
+            loop {
+                let mut chunk = vec![0; STREAM_CHUNK_SIZE];
+                let n = entry.read(&mut chunk).with_context(context)?;
+                if n == 0 {
+                    break;
+                }
+                chunk.truncate(n);
+                if chunks_tx.blocking_send(Ok(chunk.into())).is_err() {
+                    break;
+                }
+            }
+        }
+    } else {
+        let mut tar = tar::Archive::new(file);
+        for entry in tar.entries_with_seek().with_context(context)? {
+            let entry = entry.with_context(context)?;
+            if !entry.header().entry_type().is_file() {
+                continue;
+            }
+            let src = ObjectSource::FileRange {
+                path: archive.to_owned(),
+                offset: entry.raw_file_position(),
+                len: entry.size(),
+                key: entry_key(&entry.path_bytes()),
+            };
+            if tx.blocking_send(Ok(src)).is_err() {
+                return Ok(());
+            }
+        }
+    }
+    Ok(())
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -194,4 +652,119 @@ mod test {
         let files = files_recursive(dir.to_owned(), PathBuf::new());
         assert_eq!(files.count(), 10);
     }
+
+    #[test]
+    fn test_files_recursive_filters() {
+        let tmp_dir = TempDir::new("s3-testing").unwrap();
+        let dir = tmp_dir.path();
+        std::fs::create_dir_all(dir.join("a/b")).unwrap();
+        std::fs::create_dir_all(dir.join(".hidden")).unwrap();
+        for name in &["x.jpg", "x.txt", "a/y.jpg", "a/b/z.jpg", ".hidden/h.jpg"] {
+            std::fs::write(dir.join(name), "file contents").unwrap();
+        }
+        std::fs::write(dir.join("big.jpg"), vec![0; 1000]).unwrap();
+        std::fs::write(dir.join(".s3ignore"), "a/b/\n").unwrap();
+
+        let keys = |builder: FilesRecursive| {
+            let mut keys = builder
+                .build()
+                .unwrap()
+                .map(|src| src.unwrap().get_key().to_owned())
+                .collect::<Vec<_>>();
+            keys.sort();
+            keys
+        };
+        let builder = FilesRecursive::new(dir.to_owned(), PathBuf::from("p"));
+        assert_eq!(keys(builder.clone()).len(), 7);
+        assert_eq!(
+            keys(
+                builder
+                    .clone()
+                    .include("*.jpg")
+                    .exclude("a/**")
+                    .hidden(false)
+            ),
+            vec!["p/big.jpg", "p/x.jpg"]
+        );
+        assert_eq!(
+            keys(
+                builder
+                    .clone()
+                    .include("*.jpg")
+                    .ignore_file_name(".s3ignore")
+            ),
+            vec!["p/.hidden/h.jpg", "p/a/y.jpg", "p/big.jpg", "p/x.jpg"]
+        );
+        assert_eq!(
+            keys(
+                builder
+                    .clone()
+                    .max_depth(Some(1))
+                    .max_size(100)
+                    .hidden(false)
+            ),
+            vec!["p/x.jpg", "p/x.txt"]
+        );
+        assert_eq!(keys(builder.clone().min_size(100)), vec!["p/big.jpg"]);
+        assert!(builder.include("[").build().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_files_from_tar() {
+        let tmp_dir = TempDir::new("s3-testing").unwrap();
+        let contents = |i: usize| {
+            if i == 3 {
+                // Large enough to be streamed from a compressed archive
+                (0..MAX_BUFFERED_ENTRY as usize + 100_000)
+                    .map(|i| i as u8)
+                    .collect()
+            } else {
+                format!("contents of file {}", i).into_bytes()
+            }
+        };
+        let mut builder = tar::Builder::new(vec![]);
+        for i in 0..10 {
+            let data = contents(i);
+            let mut header = tar::Header::new_gnu();
+            header.set_size(data.len() as u64);
+            header.set_mode(0o644);
+            builder
+                .append_data(&mut header, format!("dir/file_{}", i), &data[..])
+                .unwrap();
+        }
+        let tar = builder.into_inner().unwrap();
+        let mut gz = flate2::write::GzEncoder::new(vec![], flate2::Compression::default());
+        std::io::Write::write_all(&mut gz, &tar).unwrap();
+
+        let plain_path = tmp_dir.path().join("archive.tar");
+        let gz_path = tmp_dir.path().join("archive.tar.gz");
+        std::fs::write(&plain_path, &tar).unwrap();
+        std::fs::write(&gz_path, gz.finish().unwrap()).unwrap();
+
+        for (path, streamed) in [(plain_path, false), (gz_path, true)] {
+            // Streamed entries must be read before the next entry is produced
+            let entries = files_from_tar(path, "prefix/".into())
+                .and_then(|src| async move {
+                    let is_streamed = matches!(src, ObjectSource::Streamed { .. });
+                    let (stream, len) = src.create_stream().await?;
+                    let data = stream.collect().await?.into_bytes();
+                    assert_eq!(len, data.len());
+                    Ok((src.get_key().to_owned(), data, is_streamed))
+                })
+                .try_collect::<Vec<_>>()
+                .await
+                .unwrap();
+            assert_eq!(entries.len(), 10);
+            for (i, (key, data, is_streamed)) in entries.into_iter().enumerate() {
+                assert_eq!(key, format!("prefix/dir/file_{}", i));
+                assert_eq!(&data[..], &contents(i)[..]);
+                assert_eq!(is_streamed, streamed && i == 3);
+            }
+        }
+        let mut missing = Box::pin(files_from_tar(
+            tmp_dir.path().join("missing.tar"),
+            "".into(),
+        ));
+        assert!(missing.next().await.unwrap().is_err());
+    }
 }